@@ -0,0 +1,143 @@
+//! The binary file a compiled graph is saved in: a fixed header followed by the
+//! bincode-serialized `ScpGraph`. All integers are little-endian.
+//!
+//! | bytes | field                                          |
+//! |-------|------------------------------------------------|
+//! | 8     | magic, `SCPGRAPH`                              |
+//! | 4     | format version                                 |
+//! | 2     | length of the compiler version string          |
+//! | n     | compiler version, UTF-8                        |
+//! | 8     | FNV-1a hash of the source program              |
+//! | 8     | length of the payload                          |
+//! | 8     | FNV-1a checksum of the payload                 |
+//! | …     | payload                                        |
+
+use crate::ScpGraph;
+
+pub const MAGIC: &[u8; 8] = b"SCPGRAPH";
+/// Bumped whenever the header or the bincode layout of `ScpGraph` changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub format_version: u32,
+    pub compiler_version: String,
+    pub source_hash: u64,
+    pub checksum: u64,
+}
+
+#[derive(Debug)]
+pub enum ContainerError {
+    NotAGraph,
+    Truncated,
+    FormatVersion{found: u32, expected: u32},
+    Checksum{found: u64, expected: u64},
+    Decode(String),
+}
+
+impl std::fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAGraph => write!(f, "not a compiled graph file"),
+            Self::Truncated => write!(f, "file is truncated"),
+            Self::FormatVersion{found, expected} => write!(
+                f, "graph file has format version {}, but this build reads version {}; recompile the program", found, expected),
+            Self::Checksum{found, expected} => write!(
+                f, "graph file is corrupted (checksum {:016x}, expected {:016x})", found, expected),
+            Self::Decode(e) => write!(f, "could not decode graph: {}", e),
+        }
+    }
+}
+
+/// 64-bit FNV-1a, used both for the source hash and the payload checksum.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn write(graph: &ScpGraph, compiler_version: &str, source: &str) -> Vec<u8> {
+    let payload = bincode::serialize(graph).unwrap();
+    let version = compiler_version.as_bytes();
+
+    let mut out = Vec::with_capacity(payload.len() + version.len() + 38);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&(version.len() as u16).to_le_bytes());
+    out.extend_from_slice(version);
+    out.extend_from_slice(&fnv1a(source.as_bytes()).to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(&fnv1a(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ContainerError> {
+        if self.bytes.len() < n {
+            return Err(ContainerError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, ContainerError> {
+        let mut b = [0u8; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> Result<u32, ContainerError> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64, ContainerError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn read_header(bytes: &[u8]) -> Result<(Header, &[u8]), ContainerError> {
+    if !is_container(bytes) {
+        return Err(ContainerError::NotAGraph);
+    }
+    let mut r = Reader{bytes: &bytes[MAGIC.len()..]};
+
+    let format_version = r.u32()?;
+    if format_version != FORMAT_VERSION {
+        return Err(ContainerError::FormatVersion{found: format_version, expected: FORMAT_VERSION});
+    }
+    let len = r.u16()? as usize;
+    let compiler_version = String::from_utf8_lossy(r.take(len)?).into_owned();
+    let source_hash = r.u64()?;
+    let payload_len = r.u64()? as usize;
+    let checksum = r.u64()?;
+    let payload = r.take(payload_len)?;
+
+    Ok((Header{format_version, compiler_version, source_hash, checksum}, payload))
+}
+
+pub fn read(bytes: &[u8]) -> Result<(Header, ScpGraph), ContainerError> {
+    let (header, payload) = read_header(bytes)?;
+    let found = fnv1a(payload);
+    if found != header.checksum {
+        return Err(ContainerError::Checksum{found, expected: header.checksum});
+    }
+    let graph = bincode::deserialize(payload).map_err(|e| ContainerError::Decode(e.to_string()))?;
+    Ok((header, graph))
+}
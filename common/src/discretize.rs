@@ -0,0 +1,168 @@
+//! Discretization: replaces a continuous distribution by a `map-categorical` over a
+//! few representative values, so that tabular solvers and scenario trees, which
+//! need finite support, can handle it.
+
+use crate::*;
+use crate::distribution::{bulk, Table};
+use crate::scenario;
+use ndarray::Array1;
+use primitives::{Distribution, LogDensity, Primitive, Quantile};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Discretization {
+    // n bins of equal probability, each represented by its median
+    Quantile(usize),
+    // n bins of equal width over the bulk of the distribution, each represented
+    // by its midpoint and weighted by its probability
+    EqualWidth(usize),
+    // the n-point Gauss–Hermite rule, which matches the first 2n - 1 moments of a
+    // normal distribution; normal distributions only
+    GaussHermite(usize),
+}
+
+impl std::str::FromStr for Discretization {
+    type Err = String;
+
+    /// Parses `quantile:<n>`, `equal-width:<n>` or `gauss-hermite:<n>`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid discretization {}, expected quantile:<n>, equal-width:<n> or gauss-hermite:<n>", s);
+        let (method, n) = s.split_once(':').ok_or_else(invalid)?;
+        let n: usize = n.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+        match method {
+            "quantile" => Ok(Self::Quantile(n)),
+            "equal-width" => Ok(Self::EqualWidth(n)),
+            "gauss-hermite" => Ok(Self::GaussHermite(n)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The discretized form of a continuous distribution, or `None` if it already has
+/// finite support.
+pub fn discretize(d: &Distribution, method: Discretization) -> Result<Option<Distribution>, String> {
+    if scenario::outcomes(d).is_some() {
+        return Ok(None);
+    }
+    let (lo, hi) = bulk(d).ok_or_else(|| format!("{:?} cannot be discretized", d))?;
+
+    let points: Vec<(f64, f64)> = match method {
+        Discretization::Quantile(n) => {
+            let table = Table::new(|x| d.log_prob(&Primitive::from(x)), lo, hi);
+            (0..n).map(|i| {
+                let u = (i as f64 + 0.5) / n as f64;
+                let x = match d.quantile(u).and_then(|x| f64::try_from(&x).ok()) {
+                    Some(x) => x,
+                    None => table.quantile(u),
+                };
+                (x, 1. / n as f64)
+            }).collect()
+        }
+        Discretization::EqualWidth(n) => {
+            let table = Table::new(|x| d.log_prob(&Primitive::from(x)), lo, hi);
+            let width = (hi - lo) / n as f64;
+            (0..n).map(|i| (lo + (i as f64 + 0.5) * width, table.mass(lo + i as f64 * width, lo + (i + 1) as f64 * width)))
+                .filter(|(_, p)| *p > 0.)
+                .collect()
+        }
+        Discretization::GaussHermite(n) => match d {
+            Distribution::Normal{mu, sigma} => gauss_hermite(n).into_iter()
+                .map(|(x, w)| (mu + std::f64::consts::SQRT_2 * sigma * x, w / std::f64::consts::PI.sqrt()))
+                .collect(),
+            _ => return Err(String::from("Gauss–Hermite points only apply to normal distributions")),
+        },
+    };
+
+    let total: f64 = points.iter().map(|(_, p)| p).sum();
+    Ok(Some(Distribution::MappedCategorical{
+        weights: points.iter().map(|(_, p)| (p / total) as f32).collect::<Array1<f32>>(),
+        values: points.iter().map(|(x, _)| Primitive::from(*x)).collect(),
+    }))
+}
+
+/// Discretizes the distribution of every stochastic variable named in
+/// `per_variable` with its method, and every other continuous one with `global` if
+/// given. Returns the variables that changed. A distribution has to be fixed at
+/// compile time, so one whose parameters read other variables is refused.
+pub fn discretize_graph(graph: &mut ScpGraph, global: Option<Discretization>, per_variable: &[(Identifier, Discretization)]) -> Result<Vec<Identifier>, String> {
+    for (name, _) in per_variable {
+        match graph.variables.get_by_name(name) {
+            Some(var) if var.kind == VariableKind::Stochastic => {}
+            _ => return Err(format!("{} is not a stochastic variable", name)),
+        }
+    }
+
+    let mut changed = Vec::new();
+    let stochastic: Vec<VarRef> = graph.variables.iter().filter(|v| v.kind == VariableKind::Stochastic).collect();
+    for var in stochastic {
+        let name = graph.variables.name(var).clone();
+        let method = match per_variable.iter().find(|(n, _)| *n == name) {
+            Some((_, method)) => *method,
+            None => match global {
+                Some(method) => method,
+                None => continue,
+            },
+        };
+
+        let definition = &graph.variables.deref(var).definition;
+        let d = match scenario::eval(definition, definition.root(), &HashMap::new()) {
+            Ok(Primitive::Distribution(d)) => d,
+            _ if graph.dependencies_of(var).is_some_and(|d| !d.depends_on.is_empty()) => {
+                return Err(format!("the distribution of {} depends on other variables, so it cannot be discretized at compile time", name));
+            }
+            other => return Err(format!("could not evaluate the distribution of {}: {:?}", name, other)),
+        };
+        if let Some(discrete) = discretize(&d, method).map_err(|e| format!("{}: {}", name, e))? {
+            let mut tree = EvaluatedTree::new();
+            tree.push(EvalExpr::C(Primitive::from(discrete)));
+            graph.variables.variables[var.id as usize].definition = tree;
+            changed.push(name);
+        }
+    }
+    Ok(changed)
+}
+
+
+// Nodes and weights of the n-point Gauss–Hermite rule for the weight e^(-x²), by
+// Newton's method on the orthonormal Hermite polynomials (Numerical Recipes'
+// `gauher`). The weights sum to √π.
+fn gauss_hermite(n: usize) -> Vec<(f64, f64)> {
+    let pi_m4 = std::f64::consts::PI.powf(-0.25);
+    let nf = n as f64;
+    let mut x = vec![0.; n];
+    let mut w = vec![0.; n];
+    let mut z = 0.;
+    for i in 0..n.div_ceil(2) {
+        z = match i {
+            0 => (2. * nf + 1.).sqrt() - 1.85575 * (2. * nf + 1.).powf(-1. / 6.),
+            1 => z - 1.14 * nf.powf(0.426) / z,
+            2 => 1.86 * z - 0.86 * x[0],
+            3 => 1.91 * z - 0.91 * x[1],
+            _ => 2. * z - x[i - 2],
+        };
+        let mut derivative = 0.;
+        for _ in 0..100 {
+            let (mut p1, mut p2) = (pi_m4, 0.);
+            for j in 0..n {
+                let p3 = p2;
+                p2 = p1;
+                let j = j as f64;
+                p1 = z * (2. / (j + 1.)).sqrt() * p2 - (j / (j + 1.)).sqrt() * p3;
+            }
+            derivative = (2. * nf).sqrt() * p2;
+            let previous = z;
+            z = previous - p1 / derivative;
+            if (z - previous).abs() <= 3e-14 {
+                break;
+            }
+        }
+        x[i] = z;
+        x[n - 1 - i] = -z;
+        w[i] = 2. / (derivative * derivative);
+        w[n - 1 - i] = w[i];
+    }
+    // smallest first
+    x.into_iter().zip(w).rev().collect()
+}
@@ -1,9 +1,9 @@
 use crate::*;
 use crate::primitives::*;
 use ndarray::prelude::*;
-use rand::prelude::ThreadRng;
 use std::convert::TryFrom;
 use crate::primitives;
+use crate::utilities::{digamma, ln_gamma};
 
 
 macro_rules! assert_num_args {
@@ -17,9 +17,10 @@ macro_rules! assert_num_args {
 
 macro_rules! get_arg {
     ($argname:ident, $arg:expr, number, $message:expr) => {
-        let $argname = match f64::try_from_ref($arg) {
-            Some(f) => *f,
-            None => {return Err(String::from($message))},
+        let $argname = match $arg {
+            Primitive::Float(f) => *f,
+            Primitive::Int(i) => *i as f64,
+            _ => {return Err(String::from($message))},
         };
     };
     ($argname:ident, $arg:expr, integral, $message:expr) => {
@@ -49,30 +50,35 @@ use rand_distr::*;
 use rand_distr::Distribution;
 
 
+// The error for parameters `rand_distr` refuses. Parameters computed at run time,
+// or read from a graph file, haven't necessarily been through
+// `build_distribution`.
+fn invalid(d: &primitives::Distribution, e: impl std::fmt::Display) -> String {
+    format!("cannot sample {:?}: {}", d, e)
+}
+
 impl Sample for primitives::Distribution {
-    type Output = Primitive;
+    type Output = Result<Primitive, String>;
 
-    fn sample(&self, rng: &mut ThreadRng) -> Primitive {
-        match self {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<Primitive, String> {
+        Ok(match self {
             Self::Dirac{center} => Primitive::from(*center),
             Self::Kronecker{center} => Primitive::from(*center),
             Self::UniformDiscrete{a, b} => {
+                if b <= a {
+                    return Err(invalid(self, "the range is empty"));
+                }
                 Primitive::from(Uniform::from(*a..*b).sample(rng))
             }
             Self::Categorical{weights} => {
                 let l = weights.len();
 
-                if cfg!(debug_assertions) {
-                    print!("Sampling (categorical {:?})…", weights.as_slice());
-                }
-
                 let val = rng.gen::<f32>();
                 let mut ssf = 0.0;
                 for (i, x) in weights.iter().enumerate() {
                     ssf += x;
                     if val <= ssf {
-                        if cfg!(debug_assertions) { println!("  -> result: {}", i); }
-                        return Primitive::from(i);
+                        return Ok(Primitive::from(i));
                     }
                 }
                 Primitive::from(l - 1) // rounding error
@@ -80,33 +86,693 @@ impl Sample for primitives::Distribution {
             Self::MappedCategorical{weights, values} => {
                 let l = weights.len();
 
-                if cfg!(debug_assertions) {
-                    print!("Sampling (categorical {:?})…", weights.as_slice());
-                }
-
                 let val = rng.gen::<f32>();
                 let mut ssf = 0.0;
-                let mut res = -1;
                 for (i, x) in weights.iter().enumerate() {
                     ssf += x;
                     if val <= ssf {
-                        if cfg!(debug_assertions) { println!("  -> result: {}", i); }
-                        res = i as i128;
+                        return Ok(values[i].clone());
                     }
                 }
-                if res == -1 {
-                    res = (l - 1) as i128;
-                }
-                values[res as usize].clone()
+                values[l - 1].clone() // rounding error
             }
             Self::Bernoulli{p} => {
-                Primitive::from(rng.gen::<f64>() <= *p)
+                Primitive::from(rng.gen::<f64>() < *p)
+            }
+            Self::UniformContinuous{a, b} => {
+                if b <= a {
+                    return Err(invalid(self, "the range is empty"));
+                }
+                Primitive::from(Uniform::from(*a..*b).sample(rng))
+            }
+            Self::Normal{mu, sigma} => {
+                Primitive::from(Normal::new(*mu, *sigma).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Cauchy{median, scale} => {
+                Primitive::from(Cauchy::new(*median, *scale).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Beta{alpha, beta} => {
+                Primitive::from(Beta::new(*alpha, *beta).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Dirichlet{weights} => {
+                let alpha: Vec<f64> = weights.iter().map(|w| *w as f64).collect();
+                Primitive::from(Dirichlet::new(&alpha).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Exponential{lambda} => {
+                Primitive::from(Exp::new(*lambda).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Gamma{shape, rate} => {
+                // rand_distr takes the scale, 1 / rate
+                Primitive::from(Gamma::new(*shape, 1. / rate).map_err(|e| invalid(self, e))?.sample(rng))
+            }
+            Self::Binomial{n, p} => {
+                Primitive::from(Binomial::new(*n, *p).map_err(|e| invalid(self, e))?.sample(rng) as i128)
+            }
+            Self::UniformChoice{values} => {
+                values[rng.gen_range(0..values.len())].clone()
+            }
+            Self::Poisson{lambda} => {
+                Primitive::from(Poisson::new(*lambda).map_err(|e| invalid(self, e))?.sample(rng) as i128)
+            }
+            Self::Geometric{p} => {
+                Primitive::from(Geometric::new(*p).map_err(|e| invalid(self, e))?.sample(rng) as i128)
+            }
+            Self::NegativeBinomial{r, p} => {
+                // a Poisson whose rate is Gamma(r, (1 - p) / p)
+                if *p >= 1. {
+                    return Ok(Primitive::from(0));
+                }
+                let lambda = Gamma::new(*r, (1. - p) / p).map_err(|e| invalid(self, e))?.sample(rng);
+                if lambda <= 0. {
+                    return Ok(Primitive::from(0));
+                }
+                Primitive::from(Poisson::new(lambda).map_err(|e| invalid(self, e))?.sample(rng) as i128)
+            }
+            Self::Mixture{weights, components} => {
+                let i = inverse_weights(weights.iter().map(|w| *w as f64), rng.gen::<f64>());
+                components[i].sample(rng)?
+            }
+            Self::Truncated{distribution, lo, hi, mass: _} => {
+                // rejection is cheap unless [lo, hi] is far in a tail, in which case
+                // fall back to inverting the truncated CDF
+                for _ in 0..1000 {
+                    let x = distribution.sample(rng)?;
+                    if f64::try_from(&x).is_ok_and(|x| *lo <= x && x <= *hi) {
+                        return Ok(x);
+                    }
+                }
+                self.quantile(rng.gen::<f64>()).ok_or_else(|| invalid(self, "no value in the range was drawn"))?
+            }
+        })
+    }
+}
+
+
+/// Counts with unbounded support are cut off where less than this much
+/// probability remains beyond.
+pub const SUPPORT_TAIL: f64 = 1e-6;
+
+/// How many values from 0 up an unbounded count distribution needs before the mass
+/// left above them drops below `SUPPORT_TAIL`.
+pub fn tail_cutoff(d: &primitives::Distribution) -> usize {
+    let mut cdf = 0.;
+    let mut k = 0;
+    while cdf < 1. - SUPPORT_TAIL && k < 1 << 24 {
+        cdf += d.log_prob(&Primitive::from(k)).exp();
+        k += 1;
+    }
+    k
+}
+
+// `x ln y`, taken as 0 when `x` is 0 so that certain outcomes stay finite.
+fn x_ln_y(x: f64, y: f64) -> f64 {
+    if x == 0. { 0. } else { x * y.ln() }
+}
+
+impl LogDensity for primitives::Distribution {
+    fn log_prob(&self, value: &Primitive) -> f64 {
+        let x = match value {
+            Primitive::Boolean(_) | Primitive::Int(_) | Primitive::Float(_) => f64::try_from(value).ok(),
+            _ => None,
+        };
+        // the value as an integer, if it is one
+        let k = x.filter(|x| x.fract() == 0.);
+        let log = |p: f64| if p > 0. { p.ln() } else { f64::NEG_INFINITY };
+
+        match self {
+            Self::Dirac{center} => log((x == Some(*center)) as u8 as f64),
+            Self::Kronecker{center} => log((k == Some(*center as f64)) as u8 as f64),
+            Self::UniformContinuous{a, b} => match x {
+                Some(x) if *a <= x && x < *b => -(b - a).ln(),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::UniformDiscrete{a, b} => match k {
+                Some(k) if *a as f64 <= k && k < *b as f64 => -((b - a) as f64).ln(),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Categorical{weights} => match k {
+                Some(k) if k >= 0. && (k as usize) < weights.len() => log(weights[k as usize] as f64),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::MappedCategorical{weights, values} => {
+                // a value may be listed more than once
+                log(values.iter().zip(weights.iter())
+                    .filter(|(v, _)| *v == value)
+                    .map(|(_, w)| *w as f64)
+                    .sum())
+            }
+            Self::Normal{mu, sigma} => match x {
+                Some(x) => {
+                    let z = (x - mu) / sigma;
+                    -0.5 * (2. * std::f64::consts::PI).ln() - sigma.ln() - 0.5 * z * z
+                }
+                None => f64::NEG_INFINITY,
+            },
+            Self::Cauchy{median, scale} => match x {
+                Some(x) => {
+                    let z = (x - median) / scale;
+                    -(std::f64::consts::PI * scale * (1. + z * z)).ln()
+                }
+                None => f64::NEG_INFINITY,
+            },
+            Self::Beta{alpha, beta} => match x {
+                Some(x) if 0. < x && x < 1. => {
+                    (alpha - 1.) * x.ln() + (beta - 1.) * (1. - x).ln()
+                        - (ln_gamma(*alpha) + ln_gamma(*beta) - ln_gamma(alpha + beta))
+                }
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Dirichlet{weights} => {
+                let xs = match Array1::<f64>::try_from(value) {
+                    Ok(xs) if xs.len() == weights.len() => xs,
+                    _ => return f64::NEG_INFINITY,
+                };
+                if xs.iter().any(|x| *x <= 0.) || (xs.sum() - 1.).abs() > 1e-6 {
+                    return f64::NEG_INFINITY;
+                }
+                let alpha = weights.mapv(|w| w as f64);
+                alpha.iter().zip(xs.iter()).map(|(a, x)| (a - 1.) * x.ln()).sum::<f64>()
+                    + ln_gamma(alpha.sum())
+                    - alpha.iter().map(|a| ln_gamma(*a)).sum::<f64>()
+            }
+            Self::Exponential{lambda} => match x {
+                Some(x) if x >= 0. => lambda.ln() - lambda * x,
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Gamma{shape, rate} => match x {
+                Some(x) if x > 0. => {
+                    shape * rate.ln() - ln_gamma(*shape) + (shape - 1.) * x.ln() - rate * x
+                }
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Bernoulli{p} => match value {
+                Primitive::Boolean(true) => log(*p),
+                Primitive::Boolean(false) => log(1. - p),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Binomial{n, p} => match k {
+                Some(k) if k >= 0. && k <= *n as f64 => {
+                    let n = *n as f64;
+                    let ln_choose = ln_gamma(n + 1.) - ln_gamma(k + 1.) - ln_gamma(n - k + 1.);
+                    ln_choose + x_ln_y(k, *p) + x_ln_y(n - k, 1. - p)
+                }
+                _ => f64::NEG_INFINITY,
+            },
+            Self::UniformChoice{values} => {
+                log(values.iter().filter(|v| *v == value).count() as f64 / values.len() as f64)
+            }
+            Self::Poisson{lambda} => match k {
+                Some(k) if k >= 0. => x_ln_y(k, *lambda) - lambda - ln_gamma(k + 1.),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Geometric{p} => match k {
+                Some(k) if k >= 0. => p.ln() + x_ln_y(k, 1. - p),
+                _ => f64::NEG_INFINITY,
+            },
+            Self::NegativeBinomial{r, p} => match k {
+                Some(k) if k >= 0. => {
+                    ln_gamma(k + r) - ln_gamma(k + 1.) - ln_gamma(*r) + r * p.ln() + x_ln_y(k, 1. - p)
+                }
+                _ => f64::NEG_INFINITY,
+            },
+            Self::Mixture{weights, components} => {
+                // log-sum-exp over the components
+                let terms: Vec<f64> = weights.iter().zip(components.iter())
+                    .map(|(w, c)| log(*w as f64) + c.log_prob(value))
+                    .collect();
+                let max = terms.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                if max == f64::NEG_INFINITY {
+                    return max;
+                }
+                max + terms.iter().map(|t| (t - max).exp()).sum::<f64>().ln()
+            }
+            Self::Truncated{distribution, lo, hi, mass} => match x {
+                Some(x) if *lo <= x && x <= *hi => distribution.log_prob(value) - mass.ln(),
+                _ => f64::NEG_INFINITY,
+            },
+        }
+    }
+}
+
+/// Whether the distribution has discrete support, so that `log_prob` is a
+/// probability mass rather than a density.
+pub fn is_discrete(d: &primitives::Distribution) -> bool {
+    use primitives::Distribution as D;
+    match d {
+        D::Dirac{center: _}
+        | D::Kronecker{center: _}
+        | D::UniformDiscrete{a: _, b: _}
+        | D::UniformChoice{values: _}
+        | D::Categorical{weights: _}
+        | D::MappedCategorical{weights: _, values: _}
+        | D::Bernoulli{p: _}
+        | D::Binomial{n: _, p: _}
+        | D::Poisson{lambda: _}
+        | D::Geometric{p: _}
+        | D::NegativeBinomial{r: _, p: _} => true,
+        D::Mixture{weights: _, components} => components.iter().all(is_discrete),
+        D::Truncated{distribution, lo: _, hi: _, mass: _} => is_discrete(distribution),
+        D::UniformContinuous{a: _, b: _}
+        | D::Normal{mu: _, sigma: _}
+        | D::Cauchy{median: _, scale: _}
+        | D::Beta{alpha: _, beta: _}
+        | D::Dirichlet{weights: _}
+        | D::Exponential{lambda: _}
+        | D::Gamma{shape: _, rate: _} => false,
+    }
+}
+
+/// Where a continuous distribution keeps nearly all of its mass.
+pub(crate) fn bulk(d: &primitives::Distribution) -> Option<(f64, f64)> {
+    use primitives::Distribution as D;
+    let q = |u: f64| d.quantile(u).and_then(|x| f64::try_from(&x).ok());
+    match d {
+        D::UniformContinuous{a, b} => Some((*a, *b)),
+        D::Beta{alpha: _, beta: _} => Some((0., 1.)),
+        D::Gamma{shape, rate} => Some((0., (shape + 12. * shape.sqrt()) / rate)),
+        // the tails are too heavy to cover, so the outer bins absorb them
+        D::Cauchy{median: _, scale: _} => Some((q(1e-3)?, q(1. - 1e-3)?)),
+        D::Normal{mu: _, sigma: _} | D::Exponential{lambda: _} => Some((q(1e-7)?, q(1. - 1e-7)?)),
+        D::Mixture{weights: _, components} => components.iter()
+            .map(bulk)
+            .try_fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), b| b.map(|(a, b)| (lo.min(a), hi.max(b)))),
+        D::Truncated{distribution, lo, hi, mass: _} => {
+            let (a, b) = bulk(distribution)?;
+            Some((a.max(*lo), b.min(*hi))).filter(|(a, b)| a < b)
+        }
+        _ => None,
+    }
+}
+
+// The mass over a fine grid of cells of a density given by its logarithm, by the
+// midpoint rule and rescaled so that whatever lies outside the grid is spread
+// over it.
+pub(crate) struct Table {
+    lo: f64,
+    width: f64,
+    // cumulative mass at the right edge of each cell
+    cumulative: Vec<f64>,
+}
+
+impl Table {
+    const CELLS: usize = 4096;
+
+    pub(crate) fn new(log_density: impl Fn(f64) -> f64, lo: f64, hi: f64) -> Self {
+        let width = (hi - lo) / Self::CELLS as f64;
+        let mut cumulative = Vec::with_capacity(Self::CELLS);
+        let mut sum = 0.;
+        for i in 0..Self::CELLS {
+            let density = log_density(lo + (i as f64 + 0.5) * width).exp();
+            sum += if density.is_finite() { density * width } else { 0. };
+            cumulative.push(sum);
+        }
+        for c in cumulative.iter_mut() {
+            *c /= sum;
+        }
+        Table{lo, width, cumulative}
+    }
+
+    // the cumulative mass at `x`, interpolated within a cell
+    fn cdf(&self, x: f64) -> f64 {
+        let at = ((x - self.lo) / self.width).clamp(0., Self::CELLS as f64);
+        let cell = (at as usize).min(Self::CELLS - 1);
+        let before = if cell == 0 { 0. } else { self.cumulative[cell - 1] };
+        before + (self.cumulative[cell] - before) * (at - cell as f64)
+    }
+
+    pub(crate) fn mass(&self, from: f64, to: f64) -> f64 {
+        self.cdf(to) - self.cdf(from)
+    }
+
+    pub(crate) fn quantile(&self, u: f64) -> f64 {
+        let cell = self.cumulative.partition_point(|c| *c < u).min(Self::CELLS - 1);
+        let before = if cell == 0 { 0. } else { self.cumulative[cell - 1] };
+        let within = (self.cumulative[cell] - before).max(f64::MIN_POSITIVE);
+        self.lo + (cell as f64 + ((u - before) / within).clamp(0., 1.)) * self.width
+    }
+}
+
+// The index of the first outcome whose cumulative weight passes `u` of the total.
+fn inverse_weights(weights: impl Iterator<Item = f64> + Clone, u: f64) -> usize {
+    let target = u * weights.clone().sum::<f64>();
+    let mut last = 0;
+    let mut sum = 0.;
+    for (i, w) in weights.enumerate() {
+        sum += w;
+        if w > 0. {
+            last = i;
+            if sum > target {
+                return i;
+            }
+        }
+    }
+    last // rounding error
+}
+
+/// The standard normal quantile, by Acklam's rational approximation (relative
+/// error below 1.2e-9).
+pub fn standard_normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02, 1.38357751867269e+02, -3.066479806614716e+01, 2.506628277459239e+00];
+    const B: [f64; 5] = [-5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02, 6.680131188771972e+01, -1.328068155288572e+01];
+    const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00, -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00];
+    const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00, 3.754408661907416e+00];
+    const LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.)
+    };
+    if p < LOW {
+        tail((-2. * p.ln()).sqrt())
+    } else if p <= 1. - LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.)
+    } else {
+        -tail((-2. * (1. - p).ln()).sqrt())
+    }
+}
+
+impl Quantile for primitives::Distribution {
+    fn quantile(&self, u: f64) -> Option<Primitive> {
+        // continuous quantiles diverge at 0 and 1
+        let open = u.clamp(f64::EPSILON, 1. - f64::EPSILON);
+        match self {
+            Self::Dirac{center} => Some(Primitive::from(*center)),
+            Self::Kronecker{center} => Some(Primitive::from(*center)),
+            Self::UniformContinuous{a, b} => Some(Primitive::from(a + (b - a) * u)),
+            Self::UniformDiscrete{a, b} => {
+                let n = b - a;
+                Some(Primitive::from(a + ((u * n as f64) as i128).min(n - 1)))
+            }
+            Self::Categorical{weights} => {
+                Some(Primitive::from(inverse_weights(weights.iter().map(|w| *w as f64), u)))
+            }
+            Self::MappedCategorical{weights, values} => {
+                Some(values[inverse_weights(weights.iter().map(|w| *w as f64), u)].clone())
+            }
+            Self::Bernoulli{p} => Some(Primitive::from(u >= 1. - p)),
+            Self::Binomial{n, p} => {
+                // walk the pmf up from (1 - p)^n
+                let n = *n as i128;
+                if *p >= 1. {
+                    return Some(Primitive::from(n));
+                }
+                let mut pmf = (1. - p).powi(n as i32);
+                let mut cdf = pmf;
+                let mut k = 0;
+                while cdf <= u && k < n {
+                    pmf *= (n - k) as f64 / (k + 1) as f64 * p / (1. - p);
+                    cdf += pmf;
+                    k += 1;
+                }
+                Some(Primitive::from(k))
+            }
+            Self::UniformChoice{values} => {
+                Some(values[((u * values.len() as f64) as usize).min(values.len() - 1)].clone())
+            }
+            Self::Poisson{lambda: _} | Self::NegativeBinomial{r: _, p: _} => {
+                // walk the pmf up to where the CDF passes `u`
+                let mut k = 0;
+                let mut cdf = self.log_prob(&Primitive::from(0)).exp();
+                while cdf <= u && k < 1 << 24 {
+                    k += 1;
+                    cdf += self.log_prob(&Primitive::from(k)).exp();
+                }
+                Some(Primitive::from(k))
+            }
+            Self::Geometric{p} => {
+                // P(X <= k) = 1 - (1 - p)^(k + 1)
+                let k = if *p >= 1. { 0. } else { ((1. - u).ln() / (1. - p).ln()).ceil() - 1. };
+                Some(Primitive::from(k.max(0.) as i128))
+            }
+            Self::Normal{mu, sigma} => Some(Primitive::from(mu + sigma * standard_normal_quantile(open))),
+            Self::Cauchy{median, scale} => {
+                Some(Primitive::from(median + scale * (std::f64::consts::PI * (open - 0.5)).tan()))
+            }
+            Self::Exponential{lambda} => Some(Primitive::from(-(1. - open).ln() / lambda)),
+            Self::Beta{alpha: _, beta: _}
+            | Self::Gamma{shape: _, rate: _}
+            | Self::Dirichlet{weights: _} => None,
+            Self::Mixture{weights: _, components: _}
+            | Self::Truncated{distribution: _, lo: _, hi: _, mass: _} => Some(Numeric::new(self)?.quantile(u)),
+        }
+    }
+}
+
+/// The quantile function of one distribution, for drawing many times from it:
+/// mixtures and truncations have no closed form, so their outcomes or CDF table
+/// are worked out once here rather than on every draw.
+pub struct Inverse {
+    pub distribution: primitives::Distribution,
+    numeric: Option<Numeric>,
+}
+
+impl Inverse {
+    pub fn new(distribution: primitives::Distribution) -> Self {
+        let numeric = Numeric::new(&distribution);
+        Inverse{distribution, numeric}
+    }
+
+    pub fn quantile(&self, u: f64) -> Option<Primitive> {
+        match &self.numeric {
+            Some(numeric) => Some(numeric.quantile(u)),
+            None if needs_numeric(&self.distribution) => None,
+            None => self.distribution.quantile(u),
+        }
+    }
+}
+
+fn needs_numeric(d: &primitives::Distribution) -> bool {
+    matches!(d, primitives::Distribution::Mixture{weights: _, components: _}
+        | primitives::Distribution::Truncated{distribution: _, lo: _, hi: _, mass: _})
+}
+
+// The inverse CDF of a mixture or truncation.
+enum Numeric {
+    // a discrete distribution's outcomes, with their probabilities
+    Outcomes(Vec<Primitive>, Vec<f64>),
+    Table(Table),
+}
+
+impl Numeric {
+    fn new(d: &primitives::Distribution) -> Option<Self> {
+        if !needs_numeric(d) {
+            None
+        } else if is_discrete(d) {
+            let values = d.values()?;
+            let weights = values.iter().map(|v| d.log_prob(v).exp()).collect();
+            Some(Numeric::Outcomes(values, weights))
+        } else {
+            let (lo, hi) = bulk(d)?;
+            Some(Numeric::Table(Table::new(|x| d.log_prob(&Primitive::from(x)), lo, hi)))
+        }
+    }
+
+    fn quantile(&self, u: f64) -> Primitive {
+        match self {
+            Numeric::Outcomes(values, weights) => values[inverse_weights(weights.iter().copied(), u)].clone(),
+            // continuous quantiles diverge at 0 and 1
+            Numeric::Table(table) => Primitive::from(table.quantile(u.clamp(f64::EPSILON, 1. - f64::EPSILON))),
+        }
+    }
+}
+
+
+impl Moments for primitives::Distribution {
+    fn mean(&self) -> Option<f64> {
+        match self {
+            Self::Dirac{center} => Some(*center),
+            Self::Kronecker{center} => Some(*center as f64),
+            Self::UniformContinuous{a, b} => Some((a + b) / 2.),
+            Self::UniformDiscrete{a, b} => Some((a + b - 1) as f64 / 2.),
+            Self::Normal{mu, sigma: _} => Some(*mu),
+            Self::Cauchy{median: _, scale: _} => None,
+            Self::Beta{alpha, beta} => Some(alpha / (alpha + beta)),
+            // vector-valued
+            Self::Dirichlet{weights: _} => None,
+            Self::Exponential{lambda} => Some(1. / lambda),
+            Self::Gamma{shape, rate} => Some(shape / rate),
+            Self::Bernoulli{p} => Some(*p),
+            Self::Binomial{n, p} => Some(*n as f64 * p),
+            Self::Poisson{lambda} => Some(*lambda),
+            Self::Geometric{p} => Some((1. - p) / p),
+            Self::NegativeBinomial{r, p} => Some(r * (1. - p) / p),
+            Self::Mixture{weights, components} => weights.iter().zip(components.iter())
+                .map(|(w, c)| Some(*w as f64 * c.mean()?))
+                .sum(),
+            Self::UniformChoice{values: _}
+            | Self::Categorical{weights: _}
+            | Self::MappedCategorical{weights: _, values: _}
+            | Self::Truncated{distribution: _, lo: _, hi: _, mass: _} => expect(self, |x| x),
+        }
+    }
+
+    fn variance(&self) -> Option<f64> {
+        match self {
+            Self::Dirac{center: _} | Self::Kronecker{center: _} => Some(0.),
+            Self::UniformContinuous{a, b} => Some((b - a).powi(2) / 12.),
+            Self::UniformDiscrete{a, b} => Some((((b - a) as f64).powi(2) - 1.) / 12.),
+            Self::Normal{mu: _, sigma} => Some(sigma * sigma),
+            Self::Cauchy{median: _, scale: _} => None,
+            Self::Beta{alpha, beta} => Some(alpha * beta / ((alpha + beta).powi(2) * (alpha + beta + 1.))),
+            Self::Dirichlet{weights: _} => None,
+            Self::Exponential{lambda} => Some(1. / (lambda * lambda)),
+            Self::Gamma{shape, rate} => Some(shape / (rate * rate)),
+            Self::Bernoulli{p} => Some(p * (1. - p)),
+            Self::Binomial{n, p} => Some(*n as f64 * p * (1. - p)),
+            Self::Poisson{lambda} => Some(*lambda),
+            Self::Geometric{p} => Some((1. - p) / (p * p)),
+            Self::NegativeBinomial{r, p} => Some(r * (1. - p) / (p * p)),
+            Self::Mixture{weights, components} => {
+                // the mean of the components' second moments, less the squared mean
+                let second: Option<f64> = weights.iter().zip(components.iter())
+                    .map(|(w, c)| Some(*w as f64 * (c.variance()? + c.mean()?.powi(2))))
+                    .sum();
+                Some(second? - self.mean()?.powi(2))
+            }
+            Self::UniformChoice{values: _}
+            | Self::Categorical{weights: _}
+            | Self::MappedCategorical{weights: _, values: _}
+            | Self::Truncated{distribution: _, lo: _, hi: _, mass: _} => {
+                let mean = self.mean()?;
+                expect(self, |x| (x - mean).powi(2))
+            }
+        }
+    }
+
+    fn min(&self) -> Option<f64> {
+        match self {
+            Self::UniformContinuous{a, b: _} => Some(*a),
+            Self::Normal{mu: _, sigma: _} | Self::Cauchy{median: _, scale: _} => Some(f64::NEG_INFINITY),
+            Self::Beta{alpha: _, beta: _} | Self::Exponential{lambda: _} | Self::Gamma{shape: _, rate: _} => Some(0.),
+            Self::Dirichlet{weights: _} => None,
+            Self::Poisson{lambda: _} | Self::Geometric{p: _} | Self::NegativeBinomial{r: _, p: _} => Some(0.),
+            Self::Mixture{weights: _, components} => {
+                components.iter().map(|c| c.min()).collect::<Option<Vec<f64>>>()?.into_iter().reduce(f64::min)
             }
-            _ => todo!()
+            Self::Truncated{distribution, lo, hi: _, mass: _} => match distribution.min()? {
+                min if min >= *lo => Some(min),
+                // the smallest value kept
+                _ if is_discrete(self) => smallest(self),
+                _ => Some(*lo),
+            },
+            _ => smallest(self),
+        }
+    }
+
+    fn max(&self) -> Option<f64> {
+        match self {
+            Self::UniformContinuous{a: _, b} => Some(*b),
+            Self::Normal{mu: _, sigma: _} | Self::Cauchy{median: _, scale: _} => Some(f64::INFINITY),
+            Self::Beta{alpha: _, beta: _} => Some(1.),
+            Self::Exponential{lambda: _} | Self::Gamma{shape: _, rate: _} => Some(f64::INFINITY),
+            Self::Dirichlet{weights: _} => None,
+            Self::Poisson{lambda: _} | Self::Geometric{p: _} | Self::NegativeBinomial{r: _, p: _} => Some(f64::INFINITY),
+            Self::Binomial{n, p} => Some(if *p > 0. { *n as f64 } else { 0. }),
+            Self::Mixture{weights: _, components} => {
+                components.iter().map(|c| c.max()).collect::<Option<Vec<f64>>>()?.into_iter().reduce(f64::max)
+            }
+            Self::Truncated{distribution, lo: _, hi, mass: _} => match distribution.max()? {
+                max if max <= *hi => Some(max),
+                _ if is_discrete(self) => largest(self),
+                _ => Some(*hi),
+            },
+            _ => largest(self),
+        }
+    }
+
+    fn entropy(&self) -> Option<f64> {
+        use std::f64::consts::{E, PI};
+        match self {
+            Self::UniformContinuous{a, b} => Some((b - a).ln()),
+            Self::UniformDiscrete{a, b} => Some(((b - a) as f64).ln()),
+            Self::Normal{mu: _, sigma} => Some(0.5 * (2. * PI * E * sigma * sigma).ln()),
+            Self::Cauchy{median: _, scale} => Some((4. * PI * scale).ln()),
+            Self::Beta{alpha, beta} => Some(
+                ln_gamma(*alpha) + ln_gamma(*beta) - ln_gamma(alpha + beta)
+                    - (alpha - 1.) * digamma(*alpha) - (beta - 1.) * digamma(*beta)
+                    + (alpha + beta - 2.) * digamma(alpha + beta)
+            ),
+            Self::Dirichlet{weights} => {
+                let alpha: Vec<f64> = weights.iter().map(|w| *w as f64).collect();
+                let total: f64 = alpha.iter().sum();
+                let k = alpha.len() as f64;
+                Some(alpha.iter().map(|a| ln_gamma(*a) - (a - 1.) * digamma(*a)).sum::<f64>()
+                    - ln_gamma(total) + (total - k) * digamma(total))
+            }
+            Self::Exponential{lambda} => Some(1. - lambda.ln()),
+            Self::Gamma{shape, rate} => Some(shape - rate.ln() + ln_gamma(*shape) + (1. - shape) * digamma(*shape)),
+            Self::Geometric{p} if *p < 1. => Some(-((1. - p) * (1. - p).ln() + p * p.ln()) / p),
+            _ if is_discrete(self) => {
+                Some(crate::scenario::outcomes(self)?.iter().map(|(_, p)| -x_ln_y(*p, *p)).sum())
+            }
+            // no closed form for continuous mixtures and truncations
+            _ => expect(self, |x| -self.log_prob(&Primitive::from(x))),
         }
     }
 }
 
+// The outcomes of a finite distribution as numbers, if they all are.
+fn numeric_outcomes(d: &primitives::Distribution) -> Option<Vec<(f64, f64)>> {
+    crate::scenario::outcomes(d)?.into_iter()
+        .map(|(v, p)| f64::try_from(&v).ok().map(|x| (x, p)))
+        .collect()
+}
+
+// The smallest and largest values a finite distribution takes with positive
+// probability.
+fn smallest(d: &primitives::Distribution) -> Option<f64> {
+    numeric_outcomes(d)?.into_iter().filter(|(_, p)| *p > 0.).map(|(x, _)| x).reduce(f64::min)
+}
+
+fn largest(d: &primitives::Distribution) -> Option<f64> {
+    numeric_outcomes(d)?.into_iter().filter(|(_, p)| *p > 0.).map(|(x, _)| x).reduce(f64::max)
+}
+
+// The expectation of `f`: a sum over the outcomes of a finite distribution, or the
+// integral over the bulk of a continuous one by the midpoint rule.
+fn expect(d: &primitives::Distribution, f: impl Fn(f64) -> f64) -> Option<f64> {
+    if is_discrete(d) {
+        return Some(numeric_outcomes(d)?.into_iter().map(|(x, p)| p * f(x)).sum());
+    }
+    let (lo, hi) = bulk(d)?;
+    let width = (hi - lo) / Table::CELLS as f64;
+    let (mut total, mut mass) = (0., 0.);
+    for i in 0..Table::CELLS {
+        let x = lo + (i as f64 + 0.5) * width;
+        let p = d.log_prob(&Primitive::from(x)).exp() * width;
+        if p > 0. && p.is_finite() {
+            total += p * f(x);
+            mass += p;
+        }
+    }
+    Some(total / mass)
+}
+
+// Merges repeated samples into one value carrying their total weight. Numeric
+// values are sorted, so that the quantile walks them in order.
+fn empirical(samples: Vec<Primitive>, weights: Array1<f32>) -> primitives::Distribution {
+    let mut merged: Vec<(Primitive, f32)> = Vec::new();
+    for (sample, w) in samples.into_iter().zip(weights.iter()) {
+        match merged.iter_mut().find(|(v, _)| *v == sample) {
+            Some((_, total)) => *total += w,
+            None => merged.push((sample, *w)),
+        }
+    }
+    let numbers: Option<Vec<f64>> = merged.iter().map(|(v, _)| f64::try_from(v).ok()).collect();
+    if let Some(numbers) = numbers {
+        let mut order: Vec<usize> = (0..merged.len()).collect();
+        order.sort_by(|a, b| numbers[*a].total_cmp(&numbers[*b]));
+        merged = order.into_iter().map(|i| merged[i].clone()).collect();
+    }
+    let total: f32 = merged.iter().map(|(_, w)| w).sum();
+    primitives::Distribution::MappedCategorical{
+        weights: merged.iter().map(|(_, w)| w / total).collect(),
+        values: merged.into_iter().map(|(v, _)| v).collect(),
+    }
+}
 
 pub fn build_distribution(dtype: DistributionType, args: &[Primitive]) -> Result<primitives::Distribution, String> {
     match dtype {
@@ -125,8 +791,8 @@ pub fn build_distribution(dtype: DistributionType, args: &[Primitive]) -> Result
             get_arg!(a, &args[0], number, "(uniform-continuous a b) requires `a` numeric.");
             get_arg!(b, &args[1], number, "(uniform-continuous a b) requires `b` numeric.");
 
-            if b < a {
-                return Err(String::from("(uniform-continuous a b) requires a <= b."));
+            if b <= a {
+                return Err(String::from("(uniform-continuous a b) requires a < b."));
             }
 
             Ok(primitives::Distribution::UniformContinuous{a, b})
@@ -136,8 +802,8 @@ pub fn build_distribution(dtype: DistributionType, args: &[Primitive]) -> Result
             get_arg!(a, &args[0], integral, "(uniform-discrete a b) requires `a` numeric.");
             get_arg!(b, &args[1], integral, "(uniform-discrete a b) requires `b` numeric.");
 
-            if b < a {
-                return Err(String::from("(uniform-discrete a b) requires a <= b."));
+            if b <= a {
+                return Err(String::from("(uniform-discrete a b) requires a < b."));
             }
 
             Ok(primitives::Distribution::UniformDiscrete{a, b})
@@ -167,6 +833,28 @@ pub fn build_distribution(dtype: DistributionType, args: &[Primitive]) -> Result
                 Ok(primitives::Distribution::MappedCategorical{weights, values})
             }
         }
+        DistributionType::Empirical => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(String::from("(empirical samples) or (empirical samples weights) expected."));
+            }
+            get_arg!(samples, &args[0], list, "(empirical samples) requires `samples` to be a vector.");
+            let weights = match args.get(1) {
+                Some(weights) => {
+                    get_arg!(weights, weights, vector, "(empirical samples weights) requires `weights` to be a vector of numbers.");
+                    weights
+                }
+                None => Array1::<f32>::ones(samples.len()),
+            };
+            if samples.is_empty() {
+                Err("(empirical samples) requires at least one sample.".into())
+            } else if weights.len() != samples.len() {
+                Err("(empirical samples weights): samples and weights must have the same length".into())
+            } else if weights.iter().any(|x| *x < 0.0) || weights.sum() <= 0.0 {
+                Err("(empirical samples weights): weights must be nonnegative and not all zero".into())
+            } else {
+                Ok(empirical(samples, weights))
+            }
+        }
         DistributionType::Normal => {
             assert_num_args!("(normal mu sigma)", args, 2);
             get_arg!(mu, &args[0], number, "(normal mu sigma) requires `mu` numeric.");
@@ -249,120 +937,226 @@ pub fn build_distribution(dtype: DistributionType, args: &[Primitive]) -> Result
                 Ok(primitives::Distribution::Binomial{n: n as u64, p})
             }
         }
+        DistributionType::UniformChoice => {
+            assert_num_args!("(uniform-choice values)", args, 1);
+            get_arg!(values, &args[0], list, "(uniform-choice values) requires `values` to be a vector.");
+            if values.is_empty() {
+                Err("(uniform-choice values) requires at least one value.".into())
+            } else {
+                Ok(primitives::Distribution::UniformChoice{values})
+            }
+        }
+        DistributionType::Poisson => {
+            assert_num_args!("(poisson λ)", args, 1);
+            get_arg!(lambda, &args[0], number, "(poisson λ) requires `λ` numeric.");
+            if lambda <= 0. {
+                Err("(poisson λ) requires `λ` positive.".into())
+            } else {
+                Ok(primitives::Distribution::Poisson{lambda})
+            }
+        }
+        DistributionType::Geometric => {
+            assert_num_args!("(geometric p)", args, 1);
+            get_arg!(p, &args[0], number, "(geometric p) requires `p` numeric.");
+            if p <= 0. || p > 1. {
+                Err("(geometric p) requires p in (0, 1].".into())
+            } else {
+                Ok(primitives::Distribution::Geometric{p})
+            }
+        }
+        DistributionType::NegativeBinomial => {
+            assert_num_args!("(negative-binomial r p)", args, 2);
+            get_arg!(r, &args[0], number, "(negative-binomial r p) requires `r` numeric.");
+            get_arg!(p, &args[1], number, "(negative-binomial r p) requires `p` numeric.");
+            if r <= 0. {
+                Err("(negative-binomial r p) requires `r` positive.".into())
+            } else if p <= 0. || p > 1. {
+                Err("(negative-binomial r p) requires p in (0, 1].".into())
+            } else {
+                Ok(primitives::Distribution::NegativeBinomial{r, p})
+            }
+        }
+        DistributionType::Mixture => {
+            assert_num_args!("(mixture weights components)", args, 2);
+            get_arg!(weights, &args[0], vector, "(mixture weights components) requires `weights` to be a vector of numbers.");
+            get_arg!(components, &args[1], list, "(mixture weights components) requires `components` to be a vector.");
+            let components: Vec<primitives::Distribution> = match components.into_iter()
+                .map(|c| match c {
+                    Primitive::Distribution(d) => Some(d),
+                    _ => None,
+                })
+                .collect() {
+                Some(components) => components,
+                None => return Err("(mixture weights components) requires every component to be a distribution.".into()),
+            };
+            let mut weights = weights;
+            weights /= weights.iter().copied().sum::<f32>();
+            if weights.iter().any(|x| *x < 0.0) {
+                Err("(mixture weights components): all weights must be positive".into())
+            } else if weights.len() != components.len() || components.is_empty() {
+                Err("(mixture weights components): components and weights must have the same, nonzero length".into())
+            } else if components.iter().any(|c| matches!(c, primitives::Distribution::Dirichlet{weights: _})) {
+                Err("(mixture weights components) cannot mix Dirichlet distributions.".into())
+            } else if components.iter().any(|c| is_discrete(c) != is_discrete(&components[0])) {
+                Err("(mixture weights components) requires the components to be all discrete or all continuous.".into())
+            } else {
+                Ok(primitives::Distribution::Mixture{weights, components})
+            }
+        }
+        DistributionType::Truncate => {
+            assert_num_args!("(truncate distribution lo hi)", args, 3);
+            let distribution = match &args[0] {
+                Primitive::Distribution(d) => d.clone(),
+                _ => return Err("(truncate distribution lo hi) requires `distribution` to be a distribution.".into()),
+            };
+            get_arg!(lo, &args[1], number, "(truncate distribution lo hi) requires `lo` numeric.");
+            get_arg!(hi, &args[2], number, "(truncate distribution lo hi) requires `hi` numeric.");
+            if hi < lo {
+                return Err("(truncate distribution lo hi) requires lo <= hi.".into());
+            }
+            let mass = match distribution.values() {
+                Some(values) => values.into_iter()
+                    .filter(|v| f64::try_from(v).is_ok_and(|x| lo <= x && x <= hi))
+                    .map(|v| distribution.log_prob(&v).exp())
+                    .sum(),
+                None => match bulk(&distribution) {
+                    Some((a, b)) => Table::new(|x| distribution.log_prob(&Primitive::from(x)), a, b).mass(lo, hi),
+                    None => return Err(format!("(truncate distribution lo hi) cannot truncate {:?}.", distribution)),
+                },
+            };
+            if mass > 0. {
+                Ok(primitives::Distribution::Truncated{distribution: Box::new(distribution), lo, hi, mass})
+            } else {
+                Err("(truncate distribution lo hi): the distribution has no mass between lo and hi.".into())
+            }
+        }
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use primitives::Distribution as D;
+    use rand::rngs::StdRng;
+
+    const DRAWS: usize = 100_000;
+
+    fn draws(d: &D, rng: &mut StdRng) -> Vec<f64> {
+        (0..DRAWS).map(|_| f64::try_from(&d.sample(rng).unwrap()).unwrap()).collect()
+    }
+
+    fn mean_and_variance(xs: &[f64]) -> (f64, f64) {
+        let n = xs.len() as f64;
+        let mean = xs.iter().sum::<f64>() / n;
+        (mean, xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.))
+    }
+
+    // The sample mean within five standard errors of `mean`, and the sample
+    // variance within 5% of `variance`.
+    fn check(d: D, mean: f64, variance: f64) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (m, v) = mean_and_variance(&draws(&d, &mut rng));
+        assert!((m - mean).abs() < 5. * (variance / DRAWS as f64).sqrt(), "{:?}: mean {} instead of {}", d, m, mean);
+        assert!((v - variance).abs() < 0.05 * variance, "{:?}: variance {} instead of {}", d, v, variance);
+    }
+
+    #[test]
+    fn normal() {
+        check(D::Normal{mu: 3., sigma: 2.}, 3., 4.);
+    }
+
+    #[test]
+    fn uniform_continuous() {
+        check(D::UniformContinuous{a: -1., b: 5.}, 2., 3.);
+    }
 
+    #[test]
+    fn beta() {
+        check(D::Beta{alpha: 2., beta: 3.}, 0.4, 0.04);
+    }
+
+    #[test]
+    fn exponential() {
+        check(D::Exponential{lambda: 0.5}, 2., 4.);
+    }
+
+    #[test]
+    fn gamma() {
+        // shape / rate and shape / rate²
+        check(D::Gamma{shape: 3., rate: 2.}, 1.5, 0.75);
+    }
+
+    #[test]
+    fn binomial() {
+        check(D::Binomial{n: 20, p: 0.3}, 6., 4.2);
+    }
 
-// pub fn sample_distribution(
-//     distribution: &crate::primitives::Distribution, rng: &mut ThreadRng,
-// ) -> Result<Primitive, String> {
-//     let args = &distribution.arguments[..];
-//     match distribution.distribution {
-//         DistributionType::Dirac => {
-//             check_params!((dirac center) from args);
-//             Ok(Primitive::from(center))
-//         }
-//         DistributionType::Kronecker => {
-//             check_params!((kronecker center) from args);
-//             Ok(Primitive::from(center))
-//         }
-//         DistributionType::UniformContinuous => {
-//             check_params!((uniform-continuous a b) from args);
-//             Ok(Primitive::from(Uniform::from(a..b).sample(rng)))
-//         }
-//         DistributionType::UniformDiscrete => {
-//             check_params!((uniform-discrete a b) from args);
-//             Ok(Primitive::from(Uniform::from(a..b).sample(rng)))
-//         }
-//         DistributionType::Categorical => {
-//             check_params!((categorical weights) from args);
-//             let l = weights.len();
-
-//             if cfg!(debug_assertions) {
-//                 print!("Sampling (categorical {:?})…", weights.as_slice());
-//             }
-
-//             let val = rng.gen::<f64>();
-//             let mut ssf = 0.0;
-//             for (i, x) in weights.iter().enumerate() {
-//                 ssf += x;
-//                 if val <= ssf {
-//                     if cfg!(debug_assertions) { println!("  -> result: {}", i); }
-//                     return Ok(Primitive::from(i));
-//                 }
-//             }
-//             if cfg!(debug_assertions) { println!("  -> result: {}", l - 1); }
-//             Ok(Primitive::from(l - 1)) // rounding error
-//         }
-//         // DistributionType::LogCategorical => {
-//         //     check_params!((categorical-logit logits) from args);
-//         //     let mut logits = logits;
-//         //     // normalize
-//         //     let norm = logsumexp(&logits);
-//         //     logits -= norm;
-//         //     // convert to probabilities
-//         //     logits.mapv_inplace(|f| f.exp());
-//         //     let norm = logits.sum();
-//         //     logits /= norm;
-//         //     let weights = logits;
-//         //     // sample
-//         //     let l = weights.len();
-//         //     let val = rng.gen::<f32>();
-//         //     let mut ssf: f32 = 0.0;
-//         //     for (i, x) in weights.iter().enumerate() {
-//         //         ssf += x;
-//         //         if val <= ssf {
-//         //             return Primitive::from(i);
-//         //         }
-//         //     }
-//         //     return Primitive::from(l - 1); // rounding error
-//         // }
-//         DistributionType::Normal => {
-//             check_params!((normal mu sigma) from args);
-//             Ok(Primitive::from(Normal::new(mu, sigma).unwrap().sample(rng)))
-//         }
-//         // DistributionType::NormalStar => {
-//         //     check_params!((normal mu sigma) from args);
-//         //     let sigma = (sigma.exp() + 1.).ln();
-//         //     Primitive::from(Normal::new(mu, sigma).unwrap().sample(rng))
-//         // }
-//         DistributionType::Cauchy => {
-//             check_params!((cauchy median scale) from args);
-//             Ok(Primitive::from(Cauchy::new(median, scale).unwrap().sample(rng)))
-//         }
-//         DistributionType::Beta => {
-//             check_params!((beta alpha beta) from args);
-//             Ok(Primitive::from(Beta::new(alpha, beta).unwrap().sample(rng)))
-//         }
-//         DistributionType::Dirichlet => {
-//             check_params!((dirichlet weights) from args);
-//             Ok(Primitive::from(
-//                 Dirichlet::new(weights.as_slice().unwrap())
-//                     .unwrap()
-//                     .sample(rng)))
-//         }
-//         DistributionType::Exponential => {
-//             check_params!((exponential lambda) from args);
-//             Ok(Primitive::from(Exp::new(lambda).unwrap().sample(rng)))
-//         }
-//         DistributionType::Gamma => {
-//             check_params!((gamma shape rate) from args);
-//             Ok(Primitive::from(Gamma::new(shape, 1.0/rate).unwrap().sample(rng)))
-//         }
-//         // DistributionType::GammaStar => {
-//         //     check_params!((gamma shape rate) from args);
-//         //     let rate = (rate.exp() + 1.0).ln();
-//         //     Primitive::from(Gamma::new(shape, 1.0/rate).unwrap().sample(rng))
-//         // }
-//         DistributionType::Bernoulli => {
-//             check_params!((bernoulli p) from args);
-//             Ok(Primitive::from(rng.gen::<f64>() <= p))
-//         }
-//         DistributionType::Binomial => {
-//             check_params!((binomial n p) from args);
-//             Ok(Primitive::from(Binomial::new(n as u64, p).unwrap().sample(rng) as i128))
-//         }
-//     }
-// }
\ No newline at end of file
+    #[test]
+    fn dirichlet() {
+        let weights = array![2f32, 3., 5.];
+        let total = 10.;
+        let d = D::Dirichlet{weights: weights.clone()};
+        let mut rng = StdRng::seed_from_u64(0);
+        let samples: Vec<Array1<f64>> = (0..DRAWS).map(|_| match d.sample(&mut rng).unwrap() {
+            Primitive::EvaluatedVector(v) => v,
+            other => panic!("{:?} is not a vector", other),
+        }).collect();
+        for (i, w) in weights.iter().enumerate() {
+            let w = *w as f64;
+            let (mean, variance) = (w / total, w * (total - w) / (total * total * (total + 1.)));
+            let (m, v) = mean_and_variance(&samples.iter().map(|s| s[i]).collect::<Vec<f64>>());
+            assert!((m - mean).abs() < 5. * (variance / DRAWS as f64).sqrt(), "component {}: mean {} instead of {}", i, m, mean);
+            assert!((v - variance).abs() < 0.05 * variance, "component {}: variance {} instead of {}", i, v, variance);
+        }
+    }
+
+    #[test]
+    fn cauchy_median() {
+        // the sample median of a Cauchy has standard error πγ / 2√n
+        let (median, scale) = (1., 2.);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut xs = draws(&D::Cauchy{median, scale}, &mut rng);
+        xs.sort_by(f64::total_cmp);
+        let m = xs[DRAWS / 2];
+        assert!((m - median).abs() < 5. * std::f64::consts::PI * scale / (2. * (DRAWS as f64).sqrt()), "median {} instead of {}", m, median);
+    }
+
+    #[test]
+    fn empty_uniform_is_refused() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(D::UniformContinuous{a: 1., b: 1.}.sample(&mut rng).is_err());
+        assert!(build_distribution(DistributionType::UniformContinuous, &[Primitive::from(1.), Primitive::from(1.)]).is_err());
+        assert!(build_distribution(DistributionType::UniformDiscrete, &[Primitive::from(2), Primitive::from(2)]).is_err());
+    }
+
+    #[test]
+    fn continuous_truncation_has_no_values() {
+        let d = build_distribution(DistributionType::Truncate, &[
+            Primitive::from(D::Normal{mu: 5., sigma: 2.}), Primitive::from(0.), Primitive::from(10.),
+        ]).unwrap();
+        assert!(d.values().is_none());
+        assert!(crate::scenario::outcomes(&d).is_none());
+    }
+
+    #[test]
+    fn continuous_distributions_cannot_be_enumerated() {
+        for d in [D::Normal{mu: 0., sigma: 1.}, D::Gamma{shape: 2., rate: 1.}, D::UniformContinuous{a: 0., b: 1.}] {
+            assert_eq!(d.cardinality(), None);
+            assert_eq!(d.nth(0), None);
+            assert!(d.values().is_none());
+        }
+        let d = D::Binomial{n: 3, p: 0.5};
+        assert_eq!(d.cardinality(), Some(4));
+        assert_eq!(d.nth(3), Some(Primitive::from(3)));
+        assert_eq!(d.nth(4), None);
+    }
+
+    #[test]
+    fn invalid_parameters_are_errors() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(D::Normal{mu: 0., sigma: f64::NAN}.sample(&mut rng).is_err());
+        assert!(D::Poisson{lambda: -1.}.sample(&mut rng).is_err());
+    }
+}
@@ -3,8 +3,8 @@ use ndarray::prelude::*;
 use std::convert::{TryFrom, TryInto};
 use std::iter::FromIterator;
 
-use common::*;
-use common::primitives::*;
+use crate::*;
+use crate::primitives::*;
 
 macro_rules! assert_num_args {
     ($name:expr, $args:expr, $len:expr) => {
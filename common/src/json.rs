@@ -0,0 +1,58 @@
+//! JSON form of a compiled `ScpGraph`. See the "Graph file formats" section of the
+//! README for the layout of each type.
+
+use crate::ScpGraph;
+use serde::{Serialize, Deserialize};
+
+/// Bumped whenever the JSON layout of `ScpGraph` or anything it contains changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Document<'a> {
+    schema_version: u32,
+    graph: &'a ScpGraph,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    schema_version: u32,
+}
+
+#[derive(Deserialize)]
+struct OwnedDocument {
+    graph: ScpGraph,
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    Malformed(String),
+    SchemaVersion{found: u32, expected: u32},
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "malformed graph JSON: {}", e),
+            Self::SchemaVersion{found, expected} => write!(
+                f, "graph JSON has schema version {}, but this build reads version {}", found, expected),
+        }
+    }
+}
+
+pub fn to_json(graph: &ScpGraph) -> String {
+    serde_json::to_string_pretty(&Document{schema_version: SCHEMA_VERSION, graph}).unwrap()
+}
+
+pub fn from_json(s: &str) -> Result<ScpGraph, JsonError> {
+    let header: Header = serde_json::from_str(s).map_err(|e| JsonError::Malformed(e.to_string()))?;
+    if header.schema_version != SCHEMA_VERSION {
+        return Err(JsonError::SchemaVersion{found: header.schema_version, expected: SCHEMA_VERSION});
+    }
+    let doc: OwnedDocument = serde_json::from_str(s).map_err(|e| JsonError::Malformed(e.to_string()))?;
+    Ok(doc.graph)
+}
+
+/// Whether a file's contents look like a JSON document rather than bincode.
+pub fn is_json(bytes: &[u8]) -> bool {
+    bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
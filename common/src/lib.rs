@@ -6,10 +6,20 @@ use std::collections::HashMap;
 pub mod eqmap;
 pub mod primitives;
 pub mod distribution;
+pub mod json;
+pub mod container;
+pub mod eval;
+pub mod scenario;
+pub mod solve;
+pub mod saa;
+pub mod stats;
+pub mod utilities;
+pub mod discretize;
+pub mod random;
 use serde::{Serialize, Deserialize};
 
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Relation {
     Eq,
     Neq,
@@ -30,6 +40,18 @@ impl Relation {
             Self::Geq => "≥"
         }
     }
+
+    /// The relation that holds after swapping (or negating) both sides.
+    pub fn mirrored(&self) -> Relation {
+        match self {
+            Self::Eq => Self::Eq,
+            Self::Neq => Self::Neq,
+            Self::Lt => Self::Gt,
+            Self::Gt => Self::Lt,
+            Self::Leq => Self::Geq,
+            Self::Geq => Self::Leq,
+        }
+    }
 }
 
 
@@ -118,6 +140,77 @@ impl EvaluatedTree {
     pub fn deref<'a>(&'a self, at: ExpressionRef) -> &EvalExpr {
         &self.expressions[at.index as usize]
     }
+
+    pub fn len(&self) -> usize {
+        self.expressions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expressions.is_empty()
+    }
+
+    /// Whether the tree is only the value of a `constrain` form, which is what a
+    /// program that ends on a constraint leaves as its body.
+    pub fn is_nil(&self) -> bool {
+        let mut at = self.root();
+        loop {
+            match self.deref(at) {
+                EvalExpr::Builtin{builtin: Builtin::Nil, args: _} => return true,
+                EvalExpr::Begin(v) if v.len() == 1 => at = v[0],
+                _ => return false,
+            }
+        }
+    }
+
+    /// Copies only the nodes reachable from the root into a fresh tree, renumbering
+    /// the `ExpressionRef`s in pre-order. `Deleted` tombstones and the gaps left by
+    /// the placeholder-then-replace pattern are dropped.
+    pub fn compact(&self) -> EvaluatedTree {
+        let mut out = EvaluatedTree::new();
+        if !self.is_empty() {
+            self.copy_into(&mut out, self.root());
+        }
+        out
+    }
+
+    /// Copies the subtree rooted at `at` into `to`, returning its new root there.
+    pub fn copy_into(&self, to: &mut EvaluatedTree, at: ExpressionRef) -> ExpressionRef {
+        let placeholder = to.placeholder();
+        let remap = |args: &[ExpressionRef], to: &mut EvaluatedTree| -> Vec<ExpressionRef> {
+            args.iter().map(|arg| self.copy_into(to, *arg)).collect()
+        };
+        let copy = match self.deref(at) {
+            EvalExpr::C(c) => EvalExpr::C(c.clone()),
+            EvalExpr::Begin(v) => EvalExpr::Begin(remap(v, to)),
+            EvalExpr::Decision{id, body} => {
+                EvalExpr::Decision{id: id.clone(), body: self.copy_into(to, *body)}
+            }
+            EvalExpr::Stochastic{id, body} => {
+                EvalExpr::Stochastic{id: id.clone(), body: self.copy_into(to, *body)}
+            }
+            EvalExpr::VarRef(id) => EvalExpr::VarRef(id.clone()),
+            EvalExpr::If{predicate, consequent, alternative} => {
+                let predicate = self.copy_into(to, *predicate);
+                let consequent = self.copy_into(to, *consequent);
+                let alternative = self.copy_into(to, *alternative);
+                EvalExpr::If{predicate, consequent, alternative}
+            }
+            EvalExpr::Constrain{prob, relation, left, right} => {
+                let left = self.copy_into(to, *left);
+                let right = self.copy_into(to, *right);
+                EvalExpr::Constrain{prob: *prob, relation: *relation, left, right}
+            }
+            EvalExpr::Builtin{builtin, args} => {
+                EvalExpr::Builtin{builtin: *builtin, args: remap(args, to)}
+            }
+            EvalExpr::Distribution{distribution, args} => {
+                EvalExpr::Distribution{distribution: *distribution, args: remap(args, to)}
+            }
+            EvalExpr::Placeholder => EvalExpr::Placeholder,
+            EvalExpr::Deleted => EvalExpr::Deleted,
+        };
+        to.replace(placeholder, copy)
+    }
 }
 
 
@@ -310,6 +403,32 @@ pub struct Constraint {
     pub left: EvaluatedTree,
     pub right: EvaluatedTree,
     pub predicate: SmallVec<[Predicate; 8]>,
+    // every variable read by either side or by a predicate, sorted by id
+    pub scope: SmallVec<[VarRef; 8]>,
+    // the earliest stage after which every variable in `scope` is known
+    pub stage: u32,
+}
+
+/// One decision variable's entry in a `LinearRow`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinearTerm {
+    pub var: VarRef,
+    // may reference stochastic variables, never decision variables
+    pub coefficient: EvaluatedTree,
+}
+
+/// A constraint rewritten as `Σ coefficient·var  relation  rhs`, where the
+/// coefficients and the right-hand side only depend on stochastic variables.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinearRow {
+    // index into `ScpGraph::constraints`
+    pub constraint: u32,
+    pub relation: Relation,
+    // sorted by variable id, one entry per decision variable
+    pub terms: Vec<LinearTerm>,
+    pub rhs: EvaluatedTree,
+    // whether any coefficient or the right-hand side depends on a stochastic variable
+    pub stochastic: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -318,26 +437,75 @@ pub struct Dependency {
     pub depends_on: SmallVec<[VarRef; 8]>,
 }
 
+/// The decisions made in a stage, followed by the stochastic variables revealed
+/// at its end. Decisions in stage `m` may depend on anything revealed before `m`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stage {
+    pub decision: SmallVec<[VarRef; 8]>,
+    pub stochastic: SmallVec<[VarRef; 8]>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ScpGraph {
     pub variables: Variables,
     pub dependencies: Vec<Dependency>,
     pub constraints: Vec<Constraint>,
+    pub linear: Vec<LinearRow>,
+    // every variable, parents before children
+    pub order: Vec<VarRef>,
+    // the variables that depend on each variable, indexed by id
+    pub children: Vec<SmallVec<[VarRef; 8]>>,
+    pub stages: Vec<Stage>,
     pub body: EvaluatedTree,
+    // variables dropped by the compiler because they can't affect anything
+    pub pruned: Vec<Identifier>,
 }
 
 impl ScpGraph {
-    pub fn dependencies_of<'a>(&'a self, var: VarRef) -> Option<&'a Dependency> {
-        for d in &self.dependencies {
-            if d.this == var {
-                if d.depends_on.len() == 0 {
-                    return None;
-                } else {
-                    return Some(d);
-                }
+    /// Compacts every tree held by the graph; see `EvaluatedTree::compact`.
+    pub fn compact(&mut self) {
+        for var in self.variables.variables.iter_mut() {
+            var.definition = var.definition.compact();
+        }
+        for constraint in self.constraints.iter_mut() {
+            constraint.left = constraint.left.compact();
+            constraint.right = constraint.right.compact();
+            for pred in constraint.predicate.iter_mut() {
+                pred.pred = pred.pred.compact();
             }
         }
-        None
+        for row in self.linear.iter_mut() {
+            for term in row.terms.iter_mut() {
+                term.coefficient = term.coefficient.compact();
+            }
+            row.rhs = row.rhs.compact();
+        }
+        self.body = self.body.compact();
+    }
+
+    pub fn linear_row(&self, constraint: usize) -> Option<&LinearRow> {
+        self.linear.iter().find(|row| row.constraint as usize == constraint)
+    }
+
+    pub fn dependencies_of<'a>(&'a self, var: VarRef) -> Option<&'a Dependency> {
+        // the compiler gathers one dependency per variable, in variable order, but a
+        // graph read from JSON or SMPS needn't keep to that
+        let found = match self.dependencies.get(var.id as usize) {
+            Some(d) if d.this == var => Some(d),
+            _ => self.dependencies.iter().find(|d| d.this == var),
+        };
+        found.filter(|d| !d.depends_on.is_empty())
+    }
+
+    pub fn children_of(&self, var: VarRef) -> &[VarRef] {
+        &self.children[var.id as usize]
+    }
+
+    pub fn stage_of(&self, var: VarRef) -> Option<usize> {
+        self.stages.iter().position(|stage| match var.kind {
+            VariableKind::Decision => stage.decision.contains(&var),
+            VariableKind::Stochastic => stage.stochastic.contains(&var),
+        })
     }
 }
 
@@ -390,19 +558,27 @@ lazy_static! {
 
         distributions.insert("dirac", DistributionType::Dirac);
         distributions.insert("kronecker", DistributionType::Kronecker);
-        // distributions.insert("uniform-continuous", DistributionType::UniformContinuous);
+        distributions.insert("uniform-continuous", DistributionType::UniformContinuous);
         distributions.insert("uniform-discrete", DistributionType::UniformDiscrete);
-        // distributions.insert("uniform", DistributionType::UniformContinuous);
+        distributions.insert("uniform", DistributionType::UniformContinuous);
+        distributions.insert("uniform-choice", DistributionType::UniformChoice);
         distributions.insert("categorical", DistributionType::Categorical);
         distributions.insert("map-categorical", DistributionType::MappedCategorical);
-        // distributions.insert("normal", DistributionType::Normal);
-        // distributions.insert("cauchy", DistributionType::Cauchy);
-        // distributions.insert("beta", DistributionType::Beta);
-        // distributions.insert("dirichlet", DistributionType::Dirichlet);
-        // distributions.insert("gamma", DistributionType::Gamma);
-        // distributions.insert("exponential", DistributionType::Exponential);
+        distributions.insert("normal", DistributionType::Normal);
+        distributions.insert("cauchy", DistributionType::Cauchy);
+        distributions.insert("beta", DistributionType::Beta);
+        distributions.insert("dirichlet", DistributionType::Dirichlet);
+        distributions.insert("gamma", DistributionType::Gamma);
+        distributions.insert("exponential", DistributionType::Exponential);
         // distributions.insert("discrete", DistributionType::Categorical);
         distributions.insert("flip", DistributionType::Bernoulli);
+        distributions.insert("binomial", DistributionType::Binomial);
+        distributions.insert("poisson", DistributionType::Poisson);
+        distributions.insert("geometric", DistributionType::Geometric);
+        distributions.insert("negative-binomial", DistributionType::NegativeBinomial);
+        distributions.insert("mixture", DistributionType::Mixture);
+        distributions.insert("truncate", DistributionType::Truncate);
+        distributions.insert("empirical", DistributionType::Empirical);
 
         distributions
     };
@@ -450,6 +626,41 @@ impl Builtin {
     pub fn maybe_match(name: &str) -> Option<Self> {
         BUILTINS.get(name).and_then(|x| Some(*x))
     }
+
+    /// The name this builtin is written as in source. `Nil` has no source form.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::OneOf => "one-of",
+            Self::IntRange => "int-range",
+            Self::First => "first",
+            Self::Second => "second",
+            Self::Last => "last",
+            Self::Rest => "rest",
+            Self::Get => "get",
+            Self::Put => "put",
+            Self::Append => "append",
+            Self::Remove => "remove",
+            Self::Vector => "vector",
+            Self::HashMap => "hash-map",
+            Self::Cons => "cons",
+            Self::Conj => "conj",
+            Self::IsEmpty => "empty?",
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Sqrt => "sqrt",
+            Self::Pow => "pow",
+            Self::Abs => "abs",
+            Self::Ln => "log",
+            Self::IsLess => "<?",
+            Self::IsEqual => "=?",
+            Self::IsGreater => ">?",
+            Self::And => "and",
+            Self::Or => "or",
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -473,6 +684,18 @@ pub enum DistributionType {
 
     Binomial,
     Bernoulli,
+    Poisson,
+    Geometric,
+    NegativeBinomial,
+
+    Mixture,
+    Truncate,
+
+    // built as a `MappedCategorical`
+    Empirical,
+    // bincode numbers variants by position, so new ones go at the end; reordering
+    // them needs a `container::FORMAT_VERSION` bump
+    UniformChoice,
 }
 
 
@@ -480,6 +703,33 @@ impl DistributionType {
     pub fn maybe_match(name: &str) -> Option<DistributionType> {
         DISTRIBUTIONS.get(name).and_then(|x| Some(x.clone()))
     }
+
+    /// The name this distribution is written as in source.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dirac => "dirac",
+            Self::Kronecker => "kronecker",
+            Self::UniformContinuous => "uniform-continuous",
+            Self::UniformDiscrete => "uniform-discrete",
+            Self::UniformChoice => "uniform-choice",
+            Self::Categorical => "categorical",
+            Self::MappedCategorical => "map-categorical",
+            Self::Normal => "normal",
+            Self::Cauchy => "cauchy",
+            Self::Beta => "beta",
+            Self::Dirichlet => "dirichlet",
+            Self::Exponential => "exponential",
+            Self::Gamma => "gamma",
+            Self::Binomial => "binomial",
+            Self::Bernoulli => "flip",
+            Self::Poisson => "poisson",
+            Self::Geometric => "geometric",
+            Self::NegativeBinomial => "negative-binomial",
+            Self::Mixture => "mixture",
+            Self::Truncate => "truncate",
+            Self::Empirical => "empirical",
+        }
+    }
 }
 
 
@@ -26,7 +26,32 @@ pub trait Support {
 
 pub trait Sample {
     type Output;
-    fn sample(&self, rng: &mut ThreadRng) -> Self::Output;
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Self::Output;
+}
+
+/// The log of the probability mass of a value under a discrete distribution, or of
+/// its density under a continuous one. `-inf` outside the support.
+pub trait LogDensity {
+    fn log_prob(&self, value: &Primitive) -> f64;
+}
+
+/// The inverse of the cumulative distribution function: the smallest value whose
+/// CDF reaches `u`, for `u` in `[0, 1)`. Feeding it stratified `u`s gives Latin
+/// hypercube samples. `None` where there is no closed form.
+pub trait Quantile {
+    fn quantile(&self, u: f64) -> Option<Primitive>;
+}
+
+/// Summary statistics of a distribution. Each is `None` where it doesn't exist,
+/// e.g. the mean of a Cauchy, or where the values aren't numbers.
+pub trait Moments {
+    fn mean(&self) -> Option<f64>;
+    fn variance(&self) -> Option<f64>;
+    // the bounds of the support, possibly infinite
+    fn min(&self) -> Option<f64>;
+    fn max(&self) -> Option<f64>;
+    // in nats; the differential entropy for a continuous distribution
+    fn entropy(&self) -> Option<f64>;
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -45,27 +70,68 @@ pub enum Distribution {
     Gamma{shape: f64, rate: f64},
     Bernoulli{p: f64},
     Binomial{n: u64, p: f64},
+    Poisson{lambda: f64},
+    // failures before the first success
+    Geometric{p: f64},
+    // failures before the `r`-th success
+    NegativeBinomial{r: f64, p: f64},
+    // draws from one of the components, picked with the given weights; the
+    // components are all discrete or all continuous
+    Mixture{weights: Array1<f32>, components: Vec<Distribution>},
+    // the distribution conditioned on lying in [lo, hi], where it has probability
+    // `mass`
+    Truncated{distribution: Box<Distribution>, lo: f64, hi: f64, mass: f64},
+    // equally likely values. bincode numbers variants by position, so new ones go
+    // at the end; reordering them needs a `container::FORMAT_VERSION` bump
+    UniformChoice{values: Vec<Primitive>},
 }
 
 
-impl Support for Distribution {
-    fn cardinality(&self) -> usize {
-        match self {
+impl Distribution {
+    /// How many values a distribution with finite support takes, or `None` for a
+    /// continuous one, which has to be discretized before it can be enumerated.
+    /// Unbounded counts stop at their tail cutoff.
+    pub fn cardinality(&self) -> Option<usize> {
+        Some(match self {
             Self::Dirac{center: _} => 1,
             Self::Kronecker{center: _} => 1,
             Self::UniformDiscrete{a, b} => (b - a) as usize,
             Self::Categorical{weights} => weights.len(),
             Self::MappedCategorical{weights, values: _} => weights.len(),
             Self::Bernoulli{p: _} => 2,
-            _ => todo!()
-        }
+            Self::UniformChoice{values} => values.len(),
+            Self::Binomial{n, p: _} => *n as usize + 1,
+            Self::Poisson{lambda: _}
+            | Self::Geometric{p: _}
+            | Self::NegativeBinomial{r: _, p: _} => crate::distribution::tail_cutoff(self),
+            Self::Mixture{weights: _, components: _}
+            | Self::Truncated{distribution: _, lo: _, hi: _, mass: _} => self.values()?.len(),
+            Self::UniformContinuous{a: _, b: _}
+            | Self::Normal{mu: _, sigma: _}
+            | Self::Cauchy{median: _, scale: _}
+            | Self::Beta{alpha: _, beta: _}
+            | Self::Dirichlet{weights: _}
+            | Self::Exponential{lambda: _}
+            | Self::Gamma{shape: _, rate: _} => return None,
+        })
     }
 
-    fn nth(&self, n: usize) -> Primitive {
-        if n >= self.cardinality() {
-            panic!("n={} out of range for {:?}", n, self);
+    /// The `n`th value in the order `values` lists them, or `None` if `n` is out
+    /// of range or the distribution is continuous.
+    pub fn nth(&self, n: usize) -> Option<Primitive> {
+        if let Self::Mixture{weights: _, components: _} | Self::Truncated{distribution: _, lo: _, hi: _, mass: _} = self {
+            // listing the values is the only way to count them
+            return self.values()?.into_iter().nth(n);
         }
-        match self {
+        if n >= self.cardinality()? {
+            return None;
+        }
+        self.value_at(n)
+    }
+
+    // the `n`th value of a variant listed by index, without checking `n` is in range
+    fn value_at(&self, n: usize) -> Option<Primitive> {
+        Some(match self {
             Self::Dirac{center} => {
                 Primitive::from(*center)
             }
@@ -84,7 +150,43 @@ impl Support for Distribution {
             Self::Bernoulli{p: _} => {
                 Primitive::from(n != 0)
             }
-            _ => unimplemented!()
+            Self::UniformChoice{values} => {
+                values[n].clone()
+            }
+            Self::Binomial{n: _, p: _}
+            | Self::Poisson{lambda: _}
+            | Self::Geometric{p: _}
+            | Self::NegativeBinomial{r: _, p: _} => {
+                Primitive::from(n)
+            }
+            _ => return None,
+        })
+    }
+
+    /// Every value a distribution with finite support takes, in the order `nth`
+    /// lists them, or `None` for a continuous one. A mixture lists its components'
+    /// values in turn, skipping repeats. Unbounded counts stop at their tail cutoff.
+    pub fn values(&self) -> Option<Vec<Primitive>> {
+        if !crate::distribution::is_discrete(self) {
+            return None;
+        }
+        match self {
+            Self::Mixture{weights: _, components} => {
+                let mut values: Vec<Primitive> = Vec::new();
+                for c in components.iter() {
+                    for v in c.values()? {
+                        if !values.contains(&v) {
+                            values.push(v);
+                        }
+                    }
+                }
+                Some(values)
+            }
+            Self::Truncated{distribution, lo, hi, mass: _} => Some(distribution.values()?.into_iter()
+                .filter(|v| f64::try_from(v).is_ok_and(|x| *lo <= x && x <= *hi))
+                .collect()),
+            // counting an unbounded count's values walks its pmf, so do it once
+            _ => (0..self.cardinality()?).map(|n| self.value_at(n)).collect(),
         }
     }
 }
@@ -118,6 +220,32 @@ impl Support for Domain {
     }
 }
 
+// A domain has no distribution over it, so only its bounds are defined.
+impl Moments for Domain {
+    fn mean(&self) -> Option<f64> { None }
+    fn variance(&self) -> Option<f64> { None }
+    fn entropy(&self) -> Option<f64> { None }
+
+    fn min(&self) -> Option<f64> {
+        match self {
+            Self::OneOf(v) => numbers(v)?.into_iter().reduce(f64::min),
+            Self::IntRange(a, b) => Some(*a as f64).filter(|_| a < b),
+        }
+    }
+
+    fn max(&self) -> Option<f64> {
+        match self {
+            Self::OneOf(v) => numbers(v)?.into_iter().reduce(f64::max),
+            Self::IntRange(a, b) => Some((b - 1) as f64).filter(|_| a < b),
+        }
+    }
+}
+
+// the values as numbers, if they all are
+fn numbers(values: &[Primitive]) -> Option<Vec<f64>> {
+    values.iter().map(|p| f64::try_from(p).ok()).collect()
+}
+
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Primitive {
@@ -138,6 +266,8 @@ pub enum Primitive {
 pub fn is_const(p: &Primitive) -> bool {
     match p {
         Primitive::Boolean(_) | Primitive::Float(_) | Primitive::Int(_) | Primitive::Vector(_) | Primitive::EvaluatedVector(_) => true,
+        // so that mixtures and truncations of fixed distributions are fixed too
+        Primitive::Distribution(_) => true,
         _ => false,
     }
 }
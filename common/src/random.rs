@@ -0,0 +1,43 @@
+//! Reproducible randomness. Every draw in a run comes from a stream derived from
+//! one master seed, so two runs with the same seed make the same draws, whatever
+//! order the streams are used in.
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Seed(pub u64);
+
+impl Seed {
+    /// The given seed, or a fresh one from the OS. Print it so the run can be
+    /// repeated.
+    pub fn new(seed: Option<u64>) -> Seed {
+        Seed(seed.unwrap_or_else(|| rand::rngs::OsRng.next_u64()))
+    }
+
+    /// The stream for this seed.
+    pub fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.0)
+    }
+
+    /// The seed of the `index`-th independent child stream, e.g. one per episode
+    /// or per thread. Children of children give further independent streams.
+    pub fn child(&self, index: u64) -> Seed {
+        Seed(split_mix(self.0 ^ split_mix(index)))
+    }
+}
+
+impl std::fmt::Display for Seed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// The SplitMix64 finalizer: nearby inputs give unrelated outputs.
+fn split_mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,198 @@
+//! Sample average approximation: scenario trees built from sampled outcomes rather
+//! than the full support, so continuous and very large distributions can still be
+//! solved and exported, with the standard statistical bounds on how far the
+//! sampled problem's answer is from the true optimum.
+
+use crate::*;
+use crate::distribution::{standard_normal_quantile, Inverse};
+use crate::scenario::{self, ScenarioError, ScenarioTree};
+use crate::solve::{self, SolveError};
+use primitives::{Primitive, Sample};
+use rand::prelude::*;
+use std::collections::HashMap;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sampling {
+    // every outcome drawn independently
+    Iid,
+    // the outcomes under one node split each variable's CDF into equal strata,
+    // one draw per stratum
+    LatinHypercube,
+}
+
+/// Draws a scenario tree with `branching[k]` equally likely children per node at
+/// the k-th stage that reveals stochastic variables; the last factor repeats for
+/// any further stages. Outcomes are drawn by inverting each distribution's CDF
+/// under the values already fixed on the path, or directly from the distribution
+/// if it has no quantile function and the draws are independent.
+pub fn sample<R: Rng + ?Sized>(graph: &ScpGraph, branching: &[usize], sampling: Sampling, rng: &mut R) -> Result<ScenarioTree, ScenarioError> {
+    let levels = scenario::levels(graph)?;
+    let mut tree = ScenarioTree::new(levels.iter().map(|(_, var)| *var).collect());
+    // each variable's last distribution, which most draws share
+    let mut inverses: HashMap<u32, Inverse> = HashMap::new();
+
+    let mut frontier = vec![0];
+    let mut start = 0;
+    let mut k = 0;
+    while start < levels.len() {
+        let stage = levels[start].0;
+        let end = levels[start..].iter().position(|(s, _)| *s != stage).map_or(levels.len(), |p| start + p);
+        let b = branching[k.min(branching.len() - 1)];
+        k += 1;
+
+        let mut next = Vec::with_capacity(frontier.len() * b);
+        for node in frontier {
+            // each sample's stratum for every variable, shuffled separately per
+            // variable so that they pair up at random
+            let mut strata = vec![Vec::with_capacity(end - start); b];
+            for _ in start..end {
+                let mut order: Vec<usize> = (0..b).collect();
+                order.shuffle(rng);
+                for (sample, stratum) in strata.iter_mut().zip(order) {
+                    sample.push(stratum);
+                }
+            }
+
+            // one path through the stage's variables per sample
+            for sample in strata.iter() {
+                let mut at = node;
+                for (i, depth) in (start..end).enumerate() {
+                    let var = tree.levels[depth];
+                    let distribution = tree.distribution_at(graph, at, var)?;
+                    if inverses.get(&var.id).is_none_or(|inverse| inverse.distribution != distribution) {
+                        inverses.insert(var.id, Inverse::new(distribution));
+                    }
+                    let inverse = &inverses[&var.id];
+                    let value = match sampling {
+                        // a distribution without a quantile function can still be
+                        // drawn directly
+                        Sampling::Iid => match inverse.quantile(rng.gen::<f64>()) {
+                            Some(value) => value,
+                            None => inverse.distribution.sample(rng)
+                                .map_err(|e| ScenarioError::Eval(graph.variables.name(var).clone(), e))?,
+                        },
+                        Sampling::LatinHypercube => inverse.quantile((sample[i] as f64 + rng.gen::<f64>()) / b as f64)
+                            .ok_or_else(|| ScenarioError::NoQuantile(graph.variables.name(var).clone()))?,
+                    };
+                    at = tree.push(at, value, if i == 0 { 1. / b as f64 } else { 1. });
+                }
+                next.push(at);
+            }
+        }
+        frontier = next;
+        start = end;
+    }
+
+    Ok(tree)
+}
+
+
+/// Statistical bounds on the optimality gap of an SAA candidate, for a problem
+/// that minimises the body. The mean optimal value of independent sampled
+/// problems is biased low, so it estimates a lower bound; the candidate's cost on
+/// a larger, independent sample estimates an upper bound.
+#[derive(Clone, Debug)]
+pub struct GapEstimate {
+    pub replications: usize,
+    // mean of the replications' optimal values, and the variance of that mean
+    pub lower: f64,
+    pub lower_variance: f64,
+    // the candidate's expected body on the evaluation tree, and its variance
+    pub upper: f64,
+    pub upper_variance: f64,
+    // the first-stage decisions being judged
+    pub candidate: Vec<(Identifier, Primitive)>,
+    pub confidence: f64,
+}
+
+impl GapEstimate {
+    pub fn gap(&self) -> f64 {
+        self.upper - self.lower
+    }
+
+    /// The normal quantile for a one-sided bound at `confidence`.
+    pub fn z(&self) -> f64 {
+        standard_normal_quantile(self.confidence)
+    }
+
+    /// An upper bound on the true gap that holds with probability `confidence`.
+    pub fn gap_bound(&self) -> f64 {
+        self.gap() + self.z() * (self.lower_variance + self.upper_variance).sqrt()
+    }
+}
+
+#[derive(Debug)]
+pub enum SaaError {
+    Scenario(ScenarioError),
+    Solve(SolveError),
+    // the candidate breaks a constraint in this scenario of the evaluation tree
+    Candidate{candidate: Vec<(Identifier, Primitive)>, scenario: Vec<(Identifier, Primitive)>},
+}
+
+impl std::fmt::Display for SaaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Scenario(e) => write!(f, "{}", e),
+            Self::Solve(e) => write!(f, "{}", e),
+            Self::Candidate{candidate, scenario} => {
+                let list = |values: &[(Identifier, Primitive)]| values.iter()
+                    .map(|(name, v)| format!("{} = {:?}", name, v))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "the candidate {} breaks a constraint ", list(candidate))?;
+                match scenario.is_empty() {
+                    true => write!(f, "before anything is observed"),
+                    false => write!(f, "in the evaluation scenario {}", list(scenario)),
+                }
+            }
+        }
+    }
+}
+
+/// Mak, Morton and Wood's multiple-replication procedure: solves `replications`
+/// independent trees sampled with `branching`, takes the first replication's
+/// first-stage decisions as the candidate, and evaluates it on a fresh tree
+/// sampled with `evaluation`. The children of that tree's root are independent
+/// samples of the candidate's cost, which gives the upper bound's variance.
+pub fn estimate_gap<R: Rng + ?Sized>(
+    graph: &ScpGraph,
+    branching: &[usize],
+    evaluation: &[usize],
+    sampling: Sampling,
+    replications: usize,
+    confidence: f64,
+    rng: &mut R,
+) -> Result<GapEstimate, SaaError> {
+    let mut optima = Vec::with_capacity(replications);
+    let mut candidate = None;
+    for _ in 0..replications {
+        let tree = sample(graph, branching, sampling, rng).map_err(SaaError::Scenario)?;
+        let solution = solve::solve(graph, &tree).map_err(SaaError::Solve)?;
+        optima.push(solution.objective);
+        candidate.get_or_insert(solution.first_stage);
+    }
+    let candidate = candidate.unwrap_or_default();
+
+    let tree = sample(graph, evaluation, sampling, rng).map_err(SaaError::Scenario)?;
+    let evaluated = solve::evaluate(graph, &tree, &candidate).map_err(|e| match e {
+        SolveError::CandidateInfeasible(scenario) => SaaError::Candidate{candidate: candidate.clone(), scenario},
+        e => SaaError::Solve(e),
+    })?;
+    let costs: Vec<f64> = evaluated.branches.iter().map(|(_, v)| *v).collect();
+
+    let (lower, lower_variance) = mean_and_variance(&optima);
+    let (upper, upper_variance) = mean_and_variance(&costs);
+    Ok(GapEstimate{replications, lower, lower_variance, upper, upper_variance, candidate, confidence})
+}
+
+// the sample mean and its variance; zero variance with fewer than two samples
+fn mean_and_variance(xs: &[f64]) -> (f64, f64) {
+    let n = xs.len() as f64;
+    let mean = xs.iter().sum::<f64>() / n;
+    if xs.len() < 2 {
+        return (mean, 0.);
+    }
+    let variance = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.);
+    (mean, variance / n)
+}
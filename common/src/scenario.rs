@@ -0,0 +1,301 @@
+//! Scenario trees: every joint outcome of a graph's stochastic variables, with
+//! conditional and path probabilities, for the solvers, exporters and evaluators
+//! that need the full distribution rather than samples from it.
+
+use crate::*;
+use crate::distribution::build_distribution;
+use crate::eval::eval_builtin;
+use primitives::{Distribution, Domain, LogDensity, Primitive, Support};
+use rand::Rng;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+
+/// One node of a scenario tree. Every level of the tree fixes the next stochastic
+/// variable in stage order, so a path from the root to a leaf is one scenario.
+#[derive(Clone, Debug)]
+pub struct ScenarioNode {
+    pub parent: Option<usize>,
+    // the value of the variable this level fixes; meaningless at the root
+    pub value: Primitive,
+    pub depth: usize,
+    // probability of this value given the path to the parent
+    pub conditional: f64,
+    // probability of the whole path from the root
+    pub probability: f64,
+    pub children: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScenarioTree {
+    pub nodes: Vec<ScenarioNode>,
+    // the stochastic variable fixed at each depth, starting from depth 1
+    pub levels: Vec<VarRef>,
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    // the variable's distribution has infinite or unenumerable support
+    InfiniteSupport(Identifier),
+    // the variable's distribution reads a decision variable
+    DecisionDependent(Identifier),
+    Eval(Identifier, String),
+    // sampling needs the distribution's quantile function, which it doesn't have
+    NoQuantile(Identifier),
+}
+
+impl std::fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InfiniteSupport(id) => write!(f, "{} does not have finite support; compile with --discretize to enumerate it", id),
+            Self::DecisionDependent(id) => write!(f, "the distribution of {} depends on a decision", id),
+            Self::Eval(id, e) => write!(f, "could not evaluate the distribution of {}: {}", id, e),
+            Self::NoQuantile(id) => write!(f, "the distribution of {} cannot be sampled by inversion", id),
+        }
+    }
+}
+
+impl ScenarioTree {
+    pub fn leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |n| self.nodes[*n].children.is_empty())
+    }
+
+    /// The ancestor of `node` at `depth`, i.e. the node reached once the first
+    /// `depth` stochastic variables are fixed.
+    pub fn ancestor(&self, mut node: usize, depth: usize) -> usize {
+        while self.nodes[node].depth > depth {
+            node = self.nodes[node].parent.unwrap();
+        }
+        node
+    }
+
+    /// The value of every stochastic variable fixed on the path to `node`.
+    pub fn assignment(&self, mut node: usize, variables: &Variables) -> HashMap<Identifier, Primitive> {
+        let mut values = HashMap::new();
+        while let Some(parent) = self.nodes[node].parent {
+            let n = &self.nodes[node];
+            values.insert(variables.name(self.levels[n.depth - 1]).clone(), n.value.clone());
+            node = parent;
+        }
+        values
+    }
+
+    // A tree with just the root.
+    pub(crate) fn new(levels: Vec<VarRef>) -> Self {
+        ScenarioTree{
+            nodes: vec![ScenarioNode{
+                parent: None,
+                value: Primitive::Boolean(true),
+                depth: 0,
+                conditional: 1.,
+                probability: 1.,
+                children: Vec::new(),
+            }],
+            levels,
+        }
+    }
+
+    // Adds a child of `parent` fixing the next level to `value`.
+    pub(crate) fn push(&mut self, parent: usize, value: Primitive, conditional: f64) -> usize {
+        let child = self.nodes.len();
+        self.nodes.push(ScenarioNode{
+            parent: Some(parent),
+            value,
+            depth: self.nodes[parent].depth + 1,
+            conditional,
+            probability: self.nodes[parent].probability * conditional,
+            children: Vec::new(),
+        });
+        self.nodes[parent].children.push(child);
+        child
+    }
+
+    // The distribution of `var` given the path to `node`.
+    pub(crate) fn distribution_at(&self, graph: &ScpGraph, node: usize, var: VarRef) -> Result<Distribution, ScenarioError> {
+        conditional_distribution(graph, var, &self.assignment(node, &graph.variables))
+            .map_err(|e| ScenarioError::Eval(graph.variables.name(var).clone(), e))
+    }
+}
+
+/// Enumerates every joint outcome of the stochastic variables, fixing them one at a
+/// time in stage order, and within a stage parents before children. Each
+/// distribution is evaluated under the values already fixed on the path, so
+/// parameters may depend on earlier stochastic variables.
+pub fn build(graph: &ScpGraph) -> Result<ScenarioTree, ScenarioError> {
+    let mut tree = ScenarioTree::new(levels(graph)?.into_iter().map(|(_, var)| var).collect());
+
+    let mut frontier = vec![0];
+    for depth in 0..tree.levels.len() {
+        let var = tree.levels[depth];
+        let mut next = Vec::new();
+        for node in frontier {
+            let distribution = tree.distribution_at(graph, node, var)?;
+            let outcomes = outcomes(&distribution)
+                .ok_or_else(|| ScenarioError::InfiniteSupport(graph.variables.name(var).clone()))?;
+
+            for (value, conditional) in outcomes {
+                if conditional > 0. {
+                    next.push(tree.push(node, value, conditional));
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    Ok(tree)
+}
+
+/// The stochastic variables in the order a scenario tree fixes them, each with the
+/// index of its stage. Refuses distributions that read a decision, since those
+/// can't be fixed ahead of it.
+pub(crate) fn levels(graph: &ScpGraph) -> Result<Vec<(usize, VarRef)>, ScenarioError> {
+    let levels = stochastic_order(graph);
+    for (_, var) in levels.iter() {
+        if let Some(d) = graph.dependencies_of(*var) {
+            if d.depends_on.iter().any(|p| p.kind == VariableKind::Decision) {
+                return Err(ScenarioError::DecisionDependent(graph.variables.name(*var).clone()));
+            }
+        }
+    }
+    Ok(levels)
+}
+
+/// The stochastic variables in stage order, and within a stage parents before
+/// children, each with the index of its stage.
+pub fn stochastic_order(graph: &ScpGraph) -> Vec<(usize, VarRef)> {
+    let position = |var: &VarRef| graph.order.iter().position(|o| o == var);
+    let mut order: Vec<(usize, VarRef)> = graph.stages.iter().enumerate()
+        .flat_map(|(i, stage)| stage.stochastic.iter().map(move |var| (i, *var)))
+        .collect();
+    order.sort_by_key(|(stage, var)| (*stage, position(var)));
+    order
+}
+
+/// The distribution of a stochastic variable once the variables its parameters
+/// read, stochastic or decision, are fixed to `values`.
+pub fn conditional_distribution(graph: &ScpGraph, var: VarRef, values: &HashMap<Identifier, Primitive>) -> Result<Distribution, String> {
+    let definition = &graph.variables.deref(var).definition;
+    match eval(definition, definition.root(), values)? {
+        Primitive::Distribution(d) => Ok(d),
+        other => Err(format!("{:?} is not a distribution", other)),
+    }
+}
+
+/// How many stochastic variables are fixed before each decision, indexed by id. A
+/// decision's copy in a scenario is that scenario's ancestor at this depth, so
+/// scenarios that agree on everything observed so far share the decision.
+pub fn decision_depths(graph: &ScpGraph) -> Vec<usize> {
+    let mut depths = vec![0; graph.variables.variables.len()];
+    let mut revealed = 0;
+    for stage in graph.stages.iter() {
+        for d in stage.decision.iter() {
+            depths[d.id as usize] = revealed;
+        }
+        revealed += stage.stochastic.len();
+    }
+    depths
+}
+
+/// Plays one episode stage by stage. Each stage's decisions are fixed first, by
+/// `policy` given the decision, its domain and the support index of every
+/// stochastic value revealed in earlier stages; it returns an index into the
+/// domain. Then the stage's stochastic variables are drawn under the values
+/// fixed so far. Returns every variable's value.
+pub fn simulate<R: Rng + ?Sized>(
+    graph: &ScpGraph,
+    rng: &mut R,
+    mut policy: impl FnMut(VarRef, &Domain, &[usize], &mut R) -> usize,
+) -> Result<HashMap<Identifier, Primitive>, String> {
+    let mut values = HashMap::new();
+    let mut observed = Vec::new();
+    for stage in graph.stages.iter() {
+        for var in stage.decision.iter() {
+            let definition = &graph.variables.deref(*var).definition;
+            let value = match eval(definition, definition.root(), &values)? {
+                Primitive::Domain(d) => d.nth(policy(*var, &d, &observed, rng)),
+                other => return Err(format!("{:?} is not a domain", other)),
+            };
+            values.insert(graph.variables.name(*var).clone(), value);
+        }
+        for var in stage.stochastic.iter() {
+            let name = graph.variables.name(*var);
+            let distribution = conditional_distribution(graph, *var, &values)?;
+            let outcomes = outcomes(&distribution)
+                .ok_or_else(|| format!("{} does not have finite support; compile with --discretize to enumerate it", name))?;
+            let u = rng.gen::<f64>();
+            let mut sum = 0.;
+            let i = outcomes.iter()
+                .position(|(_, p)| {
+                    sum += p;
+                    sum > u
+                })
+                // rounding error
+                .or_else(|| outcomes.iter().rposition(|(_, p)| *p > 0.))
+                .unwrap_or(0);
+            observed.push(i);
+            values.insert(name.clone(), outcomes[i].0.clone());
+        }
+    }
+    Ok(values)
+}
+
+/// Every value in the support of a finite distribution, with its probability.
+/// Unbounded counts stop at their tail cutoff (see `Support`), with the kept mass
+/// scaled back up to 1.
+pub fn outcomes(d: &Distribution) -> Option<Vec<(Primitive, f64)>> {
+    let weight = |n: usize, value: &Primitive| match d {
+        Distribution::Dirac{center: _} | Distribution::Kronecker{center: _} => 1.,
+        Distribution::UniformDiscrete{a, b} => 1. / (b - a) as f64,
+        Distribution::UniformChoice{values} => 1. / values.len() as f64,
+        Distribution::Categorical{weights} => weights[n] as f64,
+        Distribution::MappedCategorical{weights, values: _} => weights[n] as f64,
+        Distribution::Bernoulli{p} => if n == 0 { 1. - p } else { *p },
+        _ => d.log_prob(value).exp(),
+    };
+    let outcomes: Vec<(Primitive, f64)> = d.values()?.into_iter()
+        .enumerate()
+        .map(|(n, v)| {
+            let p = weight(n, &v);
+            (v, p)
+        })
+        .collect();
+    let total: f64 = outcomes.iter().map(|(_, p)| p).sum();
+    Some(outcomes.into_iter().map(|(v, p)| (v, p / total)).collect())
+}
+
+/// Evaluates a tree with every variable it reads taken from `values`.
+pub fn eval(tree: &EvaluatedTree, at: ExpressionRef, values: &HashMap<Identifier, Primitive>) -> Result<Primitive, String> {
+    let all = |args: &[ExpressionRef]| -> Result<Vec<Primitive>, String> {
+        args.iter().map(|arg| eval(tree, *arg, values)).collect()
+    };
+    let lookup = |id: &Identifier| values.get(id).cloned().ok_or_else(|| format!("{} has no value", id));
+    match tree.deref(at) {
+        EvalExpr::C(c) => Ok(c.clone()),
+        EvalExpr::VarRef(id) => lookup(id),
+        EvalExpr::Decision{id, body: _} | EvalExpr::Stochastic{id, body: _} => lookup(id),
+        EvalExpr::Begin(v) => {
+            let mut last = Err(String::from("empty begin"));
+            for expr in v {
+                last = eval(tree, *expr, values);
+            }
+            last
+        }
+        EvalExpr::If{predicate, consequent, alternative} => {
+            match eval(tree, *predicate, values)? {
+                Primitive::Boolean(true) => eval(tree, *consequent, values),
+                Primitive::Boolean(false) => eval(tree, *alternative, values),
+                other => match f64::try_from(&other) {
+                    Ok(f) if f != 0. => eval(tree, *consequent, values),
+                    Ok(_) => eval(tree, *alternative, values),
+                    Err(_) => Err(format!("if predicate {:?} is not a boolean", other)),
+                },
+            }
+        }
+        EvalExpr::Builtin{builtin, args} => eval_builtin(*builtin, &all(args)?),
+        EvalExpr::Distribution{distribution, args} => {
+            build_distribution(*distribution, &all(args)?).map(Primitive::from)
+        }
+        EvalExpr::Constrain{prob: _, relation: _, left: _, right: _} => Err(String::from("cannot evaluate a constraint")),
+        EvalExpr::Placeholder | EvalExpr::Deleted => unreachable!(),
+    }
+}
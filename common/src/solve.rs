@@ -0,0 +1,405 @@
+//! An exact solver for graphs whose decisions have finite domains: tries every
+//! value of every decision at every node of a scenario tree and keeps the policy
+//! with the lowest expected body. It is exponential in the number of decisions,
+//! so it is for small models and for the sampled trees of `saa`.
+
+use crate::*;
+use crate::scenario::{self, ScenarioTree};
+use primitives::{Domain, Primitive, Support};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+
+#[derive(Clone, Debug)]
+pub struct Solution {
+    // expected body of the best policy; 0 for a satisfaction problem
+    pub objective: f64,
+    // the decisions made before anything is revealed
+    pub first_stage: Vec<(Identifier, Primitive)>,
+    // probability of each child of the root, and the expected body given it
+    pub branches: Vec<(f64, f64)>,
+}
+
+#[derive(Debug)]
+pub enum SolveError {
+    Unsupported(String),
+    // no policy satisfies every constraint in every scenario
+    Infeasible,
+    // with the given first-stage decisions, nothing satisfies every constraint
+    // once these stochastic values are observed
+    CandidateInfeasible(Vec<(Identifier, Primitive)>),
+    Eval(String),
+}
+
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported(s) => write!(f, "{}", s),
+            Self::Infeasible => write!(f, "no policy satisfies every constraint in every scenario"),
+            Self::CandidateInfeasible(scenario) if scenario.is_empty() => {
+                write!(f, "the first-stage decisions break a constraint before anything is observed")
+            }
+            Self::CandidateInfeasible(scenario) => {
+                let values: Vec<String> = scenario.iter().map(|(name, v)| format!("{} = {:?}", name, v)).collect();
+                write!(f, "the first-stage decisions break a constraint in the scenario {}", values.join(", "))
+            }
+            Self::Eval(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// The policy with the lowest expected body over `tree`. Chance constraints are
+/// refused; every other constraint must hold in every scenario.
+pub fn solve(graph: &ScpGraph, tree: &ScenarioTree) -> Result<Solution, SolveError> {
+    Solver::new(graph, Some(tree))?.root(None)
+}
+
+/// The best policy over `tree` once the first-stage decisions are fixed, e.g. to
+/// estimate how well a candidate found on a smaller tree does. If no policy
+/// works, the error names a scenario in which none does.
+pub fn evaluate(graph: &ScpGraph, tree: &ScenarioTree, first_stage: &[(Identifier, Primitive)]) -> Result<Solution, SolveError> {
+    Solver::new(graph, Some(tree))?.root(Some(first_stage))
+}
+
+/// The policy with the lowest expected body over every outcome. The outcomes are
+/// enumerated as the solver goes down, under the decisions already made, so a
+/// distribution may depend on earlier decisions as well as on stochastic
+/// variables.
+pub fn solve_exact(graph: &ScpGraph) -> Result<Solution, SolveError> {
+    Solver::new(graph, None)?.root(None)
+}
+
+
+type Values = HashMap<Identifier, Primitive>;
+
+// A node of the scenario tree: either one of a fixed tree's, or one enumerated on
+// the way down.
+#[derive(Clone)]
+struct Node {
+    index: Option<usize>,
+    // how many stochastic variables are fixed
+    depth: usize,
+    // probability of the path from the root
+    probability: f64,
+    // the stochastic values on the path
+    values: Values,
+}
+
+struct Solver<'a> {
+    graph: &'a ScpGraph,
+    tree: Option<&'a ScenarioTree>,
+    // the stochastic variable fixed at each depth, starting from depth 1
+    levels: Vec<VarRef>,
+    // the decisions made at each depth of the tree, shallowest first
+    groups: Vec<(usize, Vec<VarRef>)>,
+    // the constraints that can be checked as soon as each group is decided
+    early: Vec<Vec<usize>>,
+    // the rest, checked in every scenario
+    late: Vec<usize>,
+    // the stochastic values at the last node where every choice broke a
+    // constraint; failures propagate up, so this ends up the shallowest one
+    failure: RefCell<Option<Values>>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(graph: &'a ScpGraph, tree: Option<&'a ScenarioTree>) -> Result<Self, SolveError> {
+        if let Some(c) = graph.constraints.iter().position(|c| c.probability < 1.) {
+            return Err(SolveError::Unsupported(format!("constraint {} is a chance constraint", c)));
+        }
+        let levels = match tree {
+            Some(tree) => tree.levels.clone(),
+            None => scenario::stochastic_order(graph).into_iter().map(|(_, var)| var).collect(),
+        };
+
+        let depths = scenario::decision_depths(graph);
+        let mut groups: Vec<(usize, Vec<VarRef>)> = Vec::new();
+        for var in graph.order.iter().filter(|v| v.kind == VariableKind::Decision) {
+            let depth = depths[var.id as usize];
+            match groups.iter_mut().find(|(d, _)| *d == depth) {
+                Some((_, vars)) => vars.push(*var),
+                None => groups.push((depth, vec![*var])),
+            }
+        }
+        groups.sort_by_key(|(depth, _)| *depth);
+
+        // which group decides each decision, and the depth that fixes each stochastic
+        // variable, indexed by id
+        let mut group_of = vec![None; graph.variables.variables.len()];
+        for (k, (_, vars)) in groups.iter().enumerate() {
+            for var in vars {
+                group_of[var.id as usize] = Some(k);
+            }
+        }
+        let mut level_of = vec![0; graph.variables.variables.len()];
+        for (i, var) in levels.iter().enumerate() {
+            level_of[var.id as usize] = i + 1;
+        }
+
+        let mut early = vec![Vec::new(); groups.len()];
+        let mut late = Vec::new();
+        for (i, c) in graph.constraints.iter().enumerate() {
+            let group = c.scope.iter().filter_map(|var| group_of[var.id as usize]).max();
+            let depth = c.scope.iter().map(|var| level_of[var.id as usize]).max().unwrap_or(0);
+            match group {
+                Some(k) if depth <= groups[k].0 => early[k].push(i),
+                _ => late.push(i),
+            }
+        }
+
+        Ok(Solver{graph, tree, levels, groups, early, late, failure: RefCell::new(None)})
+    }
+
+    // Tries every first-stage decision, or just `fixed`, and keeps the best.
+    fn root(&self, fixed: Option<&[(Identifier, Primitive)]>) -> Result<Solution, SolveError> {
+        let root_group = self.groups.first().filter(|(depth, _)| *depth == 0);
+        let next = if root_group.is_some() { 1 } else { 0 };
+        let root = Node{index: self.tree.map(|_| 0), depth: 0, probability: 1., values: Values::new()};
+
+        let mut best: Option<Solution> = None;
+        let mut consider = |decisions: &mut Values| -> Result<(), SolveError> {
+            if root_group.is_some() && !self.all_hold(&self.early[0], &root, decisions)? {
+                return Ok(());
+            }
+            // the first stochastic variables may depend on the first-stage decisions
+            let children = match self.levels.len() {
+                0 => vec![root.clone()],
+                _ => self.children(&root, decisions)?,
+            };
+            let mut branches = Vec::with_capacity(children.len());
+            for c in children.iter() {
+                match self.best(c, next, decisions)? {
+                    Some(v) => branches.push((c.probability, v)),
+                    None => return Ok(()),
+                }
+            }
+            let objective = branches.iter().map(|(p, v)| p * v).sum();
+            if best.as_ref().is_none_or(|b| objective < b.objective) {
+                let first_stage = root_group.map(|(_, vars)| vars.iter().map(|var| {
+                    let name = self.graph.variables.name(*var);
+                    (name.clone(), decisions[name].clone())
+                }).collect()).unwrap_or_default();
+                best = Some(Solution{objective, first_stage, branches});
+            }
+            Ok(())
+        };
+
+        let mut decisions = Values::new();
+        match (fixed, root_group) {
+            (Some(fixed), _) => {
+                decisions.extend(fixed.iter().cloned());
+                consider(&mut decisions)?;
+                if best.is_none() {
+                    let failure = self.failure.take().unwrap_or_default();
+                    let scenario = self.levels.iter()
+                        .map(|var| self.graph.variables.name(*var))
+                        .filter_map(|name| failure.get(name).map(|v| (name.clone(), v.clone())))
+                        .collect();
+                    return Err(SolveError::CandidateInfeasible(scenario));
+                }
+            }
+            (None, Some((_, vars))) => self.each_choice(&root, vars, &mut decisions, &mut consider)?,
+            (None, None) => consider(&mut decisions)?,
+        }
+        best.ok_or(SolveError::Infeasible)
+    }
+
+    // The lowest expected body given `node`, deciding group `k` onwards at every
+    // node below it. `None` if every choice breaks a constraint somewhere.
+    fn best(&self, node: &Node, k: usize, decisions: &mut Values) -> Result<Option<f64>, SolveError> {
+        if k == self.groups.len() {
+            return self.leaves(node, decisions);
+        }
+        let (depth, vars) = &self.groups[k];
+        let mut total = 0.;
+        for n in self.below(node, *depth, decisions)? {
+            let mut best: Option<f64> = None;
+            self.each_choice(&n, vars, decisions, &mut |decisions| {
+                if !self.all_hold(&self.early[k], &n, decisions)? {
+                    return Ok(());
+                }
+                if let Some(v) = self.best(&n, k + 1, decisions)? {
+                    if best.is_none_or(|b| v < b) {
+                        best = Some(v);
+                    }
+                }
+                Ok(())
+            })?;
+            match best {
+                Some(v) => total += n.probability / node.probability * v,
+                None => {
+                    self.failure.replace(Some(n.values.clone()));
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(total))
+    }
+
+    // The expected body over the scenarios below `node` once every decision is made.
+    fn leaves(&self, node: &Node, decisions: &Values) -> Result<Option<f64>, SolveError> {
+        let mut total = 0.;
+        for leaf in self.below(node, self.levels.len(), decisions)? {
+            if !self.all_hold(&self.late, &leaf, decisions)? {
+                self.failure.replace(Some(leaf.values.clone()));
+                return Ok(None);
+            }
+            if !self.graph.body.is_nil() {
+                let body = self.eval(&self.graph.body, &self.scope(&leaf, decisions))?;
+                // a non-numeric body leaves nothing to minimise
+                total += leaf.probability / node.probability * f64::try_from(&body).unwrap_or(0.);
+            }
+        }
+        Ok(Some(total))
+    }
+
+    // Calls `f` with every assignment of `vars` at `node`. A domain may read the
+    // decisions before it, so they are fixed one at a time.
+    fn each_choice(&self, node: &Node, vars: &[VarRef], decisions: &mut Values, f: &mut dyn FnMut(&mut Values) -> Result<(), SolveError>) -> Result<(), SolveError> {
+        let (var, rest) = match vars.split_first() {
+            Some(split) => split,
+            None => return f(decisions),
+        };
+        let name = self.graph.variables.name(*var);
+        let definition = &self.graph.variables.deref(*var).definition;
+        let domain = match self.eval(definition, &self.scope(node, decisions))? {
+            Primitive::Domain(d) => d,
+            other => return Err(SolveError::Unsupported(format!("the domain {:?} of {} is not finite", other, name))),
+        };
+        let count = match domain {
+            Domain::IntRange(a, b) if b <= a => 0,
+            ref d => d.cardinality(),
+        };
+        for n in 0..count {
+            decisions.insert(name.clone(), domain.nth(n));
+            self.each_choice(node, rest, decisions, f)?;
+        }
+        decisions.remove(name);
+        Ok(())
+    }
+
+    fn all_hold(&self, constraints: &[usize], node: &Node, decisions: &Values) -> Result<bool, SolveError> {
+        if constraints.is_empty() {
+            return Ok(true);
+        }
+        let values = self.scope(node, decisions);
+        for c in constraints {
+            if !self.holds(&self.graph.constraints[*c], &values)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn holds(&self, c: &Constraint, values: &Values) -> Result<bool, SolveError> {
+        holds(c, values)
+    }
+
+    fn eval(&self, tree: &EvaluatedTree, values: &Values) -> Result<Primitive, SolveError> {
+        eval(tree, values)
+    }
+
+    // every value known at `node`
+    fn scope(&self, node: &Node, decisions: &Values) -> Values {
+        let mut values = node.values.clone();
+        values.extend(decisions.iter().map(|(k, v)| (k.clone(), v.clone())));
+        values
+    }
+
+    // The children of `node`: a fixed tree's, or every outcome of the next
+    // variable's distribution given the path and the decisions made so far.
+    fn children(&self, node: &Node, decisions: &Values) -> Result<Vec<Node>, SolveError> {
+        let var = self.levels[node.depth];
+        let name = self.graph.variables.name(var);
+        let child = |index: Option<usize>, value: Primitive, probability: f64| {
+            let mut values = node.values.clone();
+            values.insert(name.clone(), value);
+            Node{index, depth: node.depth + 1, probability, values}
+        };
+
+        match (self.tree, node.index) {
+            (Some(tree), Some(index)) => Ok(tree.nodes[index].children.iter()
+                .map(|c| child(Some(*c), tree.nodes[*c].value.clone(), tree.nodes[*c].probability))
+                .collect()),
+            _ => {
+                let d = scenario::conditional_distribution(self.graph, var, &self.scope(node, decisions))
+                    .map_err(SolveError::Eval)?;
+                let outcomes = scenario::outcomes(&d)
+                    .ok_or_else(|| SolveError::Unsupported(format!("{} does not have finite support; compile with --discretize to enumerate it", name)))?;
+                Ok(outcomes.into_iter()
+                    .filter(|(_, p)| *p > 0.)
+                    .map(|(value, p)| child(None, value, node.probability * p))
+                    .collect())
+            }
+        }
+    }
+
+    // the nodes at `depth` in the subtree under `node`
+    fn below(&self, node: &Node, depth: usize, decisions: &Values) -> Result<Vec<Node>, SolveError> {
+        let depth = depth.min(self.levels.len());
+        let mut found = Vec::new();
+        let mut stack = vec![node.clone()];
+        while let Some(n) = stack.pop() {
+            if n.depth >= depth {
+                found.push(n);
+            } else {
+                stack.extend(self.children(&n, decisions)?.into_iter().rev());
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// The body of one complete scenario, with every variable fixed, or `None` if a
+/// constraint fails in it. Chance constraints are checked like any other.
+pub fn cost(graph: &ScpGraph, values: &Values) -> Result<Option<f64>, SolveError> {
+    for c in graph.constraints.iter() {
+        if !holds(c, values)? {
+            return Ok(None);
+        }
+    }
+    if graph.body.is_nil() {
+        return Ok(Some(0.));
+    }
+    // a non-numeric body leaves nothing to minimise
+    Ok(Some(f64::try_from(&eval(&graph.body, values)?).unwrap_or(0.)))
+}
+
+// whether the constraint holds, or one of its predicates doesn't
+fn holds(c: &Constraint, values: &Values) -> Result<bool, SolveError> {
+    for pred in c.predicate.iter() {
+        if truthy(&eval(&pred.pred, values)?)? == pred.negated {
+            return Ok(true);
+        }
+    }
+    let left = eval(&c.left, values)?;
+    let right = eval(&c.right, values)?;
+    match (f64::try_from(&left), f64::try_from(&right)) {
+        (Ok(l), Ok(r)) => Ok(match c.relation {
+            Relation::Eq => l == r,
+            Relation::Neq => l != r,
+            Relation::Lt => l < r,
+            Relation::Gt => l > r,
+            Relation::Leq => l <= r,
+            Relation::Geq => l >= r,
+        }),
+        _ => match c.relation {
+            Relation::Eq => Ok(left == right),
+            Relation::Neq => Ok(left != right),
+            _ => Err(SolveError::Eval(format!("cannot compare {:?} and {:?}", left, right))),
+        },
+    }
+}
+
+fn eval(tree: &EvaluatedTree, values: &Values) -> Result<Primitive, SolveError> {
+    scenario::eval(tree, tree.root(), values).map_err(SolveError::Eval)
+}
+
+fn truthy(p: &Primitive) -> Result<bool, SolveError> {
+    match p {
+        Primitive::Boolean(b) => Ok(*b),
+        other => f64::try_from(other)
+            .map(|f| f != 0.)
+            .map_err(|_| SolveError::Eval(format!("predicate {:?} is not a boolean", other))),
+    }
+}
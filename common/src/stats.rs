@@ -0,0 +1,196 @@
+//! How big a compiled problem is: variable counts and supports, the state and
+//! action spaces a Q table over them would need, and how the constraints,
+//! dependencies and stages are spread out.
+
+use crate::*;
+use crate::scenario;
+use primitives::{Domain, Primitive, Support};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Parent assignments tried, and values kept, per variable before giving up.
+const ENUMERATION_LIMIT: usize = 10_000;
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Statistics {
+    pub decisions: usize,
+    pub stochastic: usize,
+    pub variables: Vec<VariableStatistics>,
+    // product of the stochastic cardinalities; `None` if any is unknown or it overflows
+    pub state_space: Option<u128>,
+    // product of the decision cardinalities, likewise
+    pub action_space: Option<u128>,
+    pub constraints: usize,
+    pub linear: usize,
+    pub constraints_by_kind: Vec<ConstraintCount>,
+    // the longest chain of variables each depending on the one before
+    pub dependency_depth: usize,
+    pub stages: Vec<StageStatistics>,
+    pub pruned: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VariableStatistics {
+    pub name: Identifier,
+    pub kind: VariableKind,
+    pub stage: Option<usize>,
+    // how many variables lie on the longest dependency chain above this one
+    pub depth: usize,
+    // the largest support over every value of its parents; `None` if infinite or
+    // too many to enumerate
+    pub cardinality: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConstraintCount {
+    pub relation: Relation,
+    pub probability: f64,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StageStatistics {
+    pub decisions: usize,
+    pub stochastic: usize,
+    // constraints that can first be checked after this stage
+    pub constraints: usize,
+}
+
+pub fn statistics(graph: &ScpGraph) -> Statistics {
+    let n = graph.variables.variables.len();
+    let mut depth = vec![0; n];
+    let mut cardinality: Vec<Option<usize>> = vec![None; n];
+    // every value each variable can take, while there are few enough to list
+    let mut values: Vec<Option<Vec<Primitive>>> = vec![None; n];
+
+    for var in graph.order.iter() {
+        let parents: &[VarRef] = graph.dependencies_of(*var).map_or(&[], |d| &d.depends_on);
+        depth[var.id as usize] = parents.iter().map(|p| depth[p.id as usize] + 1).max().unwrap_or(0);
+        if let Some((c, v)) = support(graph, *var, parents, &values) {
+            cardinality[var.id as usize] = Some(c);
+            values[var.id as usize] = v;
+        }
+    }
+
+    let variables: Vec<VariableStatistics> = graph.variables.iter().map(|var| VariableStatistics{
+        name: graph.variables.name(var).clone(),
+        kind: var.kind,
+        stage: graph.stage_of(var),
+        depth: depth[var.id as usize],
+        cardinality: cardinality[var.id as usize],
+    }).collect();
+
+    let space = |kind: VariableKind| variables.iter()
+        .filter(|v| v.kind == kind)
+        .try_fold(1u128, |product, v| product.checked_mul(v.cardinality? as u128));
+
+    let mut constraints_by_kind: Vec<ConstraintCount> = Vec::new();
+    for c in graph.constraints.iter() {
+        match constraints_by_kind.iter_mut().find(|k| k.relation == c.relation && k.probability == c.probability) {
+            Some(k) => k.count += 1,
+            None => constraints_by_kind.push(ConstraintCount{relation: c.relation, probability: c.probability, count: 1}),
+        }
+    }
+
+    let stages = graph.stages.iter().enumerate().map(|(i, stage)| StageStatistics{
+        decisions: stage.decision.len(),
+        stochastic: stage.stochastic.len(),
+        constraints: graph.constraints.iter().filter(|c| c.stage as usize == i).count(),
+    }).collect();
+
+    Statistics{
+        decisions: variables.iter().filter(|v| v.kind == VariableKind::Decision).count(),
+        stochastic: variables.iter().filter(|v| v.kind == VariableKind::Stochastic).count(),
+        state_space: space(VariableKind::Stochastic),
+        action_space: space(VariableKind::Decision),
+        variables,
+        constraints: graph.constraints.len(),
+        linear: graph.linear.len(),
+        constraints_by_kind,
+        dependency_depth: depth.iter().copied().max().unwrap_or(0),
+        stages,
+        pruned: graph.pruned.len(),
+    }
+}
+
+// The largest support of `var` over every assignment of its parents, and the union
+// of those supports if it is small enough to keep.
+fn support(graph: &ScpGraph, var: VarRef, parents: &[VarRef], values: &[Option<Vec<Primitive>>]) -> Option<(usize, Option<Vec<Primitive>>)> {
+    let choices: Vec<&Vec<Primitive>> = parents.iter().map(|p| values[p.id as usize].as_ref()).collect::<Option<_>>()?;
+    let combinations = choices.iter().try_fold(1usize, |product, c| product.checked_mul(c.len()))?;
+    if combinations > ENUMERATION_LIMIT {
+        return None;
+    }
+
+    let definition = &graph.variables.deref(var).definition;
+    let mut largest = 0;
+    let mut union: Option<Vec<Primitive>> = Some(Vec::new());
+    for i in 0..combinations {
+        // the i-th assignment, read as a mixed-radix number
+        let mut rest = i;
+        let mut assignment = HashMap::new();
+        for (p, c) in parents.iter().zip(choices.iter()) {
+            assignment.insert(graph.variables.name(*p).clone(), c[rest % c.len()].clone());
+            rest /= c.len();
+        }
+
+        let outcomes: Vec<Primitive> = match scenario::eval(definition, definition.root(), &assignment).ok()? {
+            Primitive::Distribution(d) => scenario::outcomes(&d)?.into_iter()
+                .filter(|(_, p)| *p > 0.)
+                .map(|(v, _)| v)
+                .collect(),
+            Primitive::Domain(Domain::IntRange(a, b)) if b <= a => Vec::new(),
+            Primitive::Domain(d) if d.cardinality() > ENUMERATION_LIMIT => {
+                largest = largest.max(d.cardinality());
+                union = None;
+                continue;
+            }
+            Primitive::Domain(d) => (0..d.cardinality()).map(|n| d.nth(n)).collect(),
+            _ => return None,
+        };
+        largest = largest.max(outcomes.len());
+        if let Some(u) = union.as_mut() {
+            for v in outcomes {
+                if !u.contains(&v) {
+                    u.push(v);
+                }
+            }
+            if u.len() > ENUMERATION_LIMIT {
+                union = None;
+            }
+        }
+    }
+    Some((largest, union))
+}
+
+impl std::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = |s: Option<u128>| s.map_or(String::from("unknown"), |s| s.to_string());
+        writeln!(f, "{} decision and {} stochastic variables", self.decisions, self.stochastic)?;
+        for v in self.variables.iter() {
+            let cardinality = v.cardinality.map_or(String::from("?"), |c| c.to_string());
+            let stage = v.stage.map_or(String::from("-"), |s| s.to_string());
+            writeln!(f, "{:>8}  {:?}  stage {}  depth {}  cardinality {}", v.name, v.kind, stage, v.depth, cardinality)?;
+        }
+        writeln!(f, "State space:      {}", size(self.state_space))?;
+        writeln!(f, "Action space:     {}", size(self.action_space))?;
+        writeln!(f, "Constraints:      {} ({} linear)", self.constraints, self.linear)?;
+        for k in self.constraints_by_kind.iter() {
+            writeln!(f, "{:>8}  {} with probability {}", k.count, k.relation.pretty_print(), k.probability)?;
+        }
+        writeln!(f, "Dependency depth: {}", self.dependency_depth)?;
+        for (i, s) in self.stages.iter().enumerate() {
+            writeln!(f, "Stage {}: {} decisions, {} stochastic, {} constraints", i, s.decisions, s.stochastic, s.constraints)?;
+        }
+        if self.pruned > 0 {
+            writeln!(f, "Pruned:           {}", self.pruned)?;
+        }
+        Ok(())
+    }
+}
+
+/// The report as JSON, for scripts that size a run before starting it.
+pub fn to_json(stats: &Statistics) -> String {
+    serde_json::to_string_pretty(stats).unwrap()
+}
@@ -0,0 +1,358 @@
+use crate::partial_eval::{binding_order, EE};
+use common::*;
+use primitives::{Distribution, Domain, Primitive};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+
+/// Writes a compiled graph back out as a program: one `let` binding per variable,
+/// in the order they were bound, then every constraint under `if`s for its
+/// predicates, then the body. Compiling the output gives an equivalent graph.
+pub fn decompile(graph: &ScpGraph) -> String {
+    let mut refs = graph.order.clone();
+    if refs.iter().all(|r| binding_order(graph.variables.name(*r)).is_some()) {
+        refs.sort_by_key(|r| binding_order(graph.variables.name(*r)));
+    }
+    let bindings = refs.iter()
+        .map(|r| {
+            let var = graph.variables.deref(*r);
+            (var.kind, var.name.clone(), &var.definition)
+        })
+        .collect();
+
+    let mut forms = Vec::new();
+    for constraint in graph.constraints.iter() {
+        forms.push(Form::Constraint(constraint));
+    }
+    if graph.body.is_nil() {
+        // a body that is just a constraint's value comes from ending on an
+        // unguarded constraint, so make sure one is last
+        let last = graph.constraints.iter().rposition(|c| c.predicate.is_empty());
+        if let Some(i) = last {
+            let form = forms.remove(i);
+            forms.push(form);
+        }
+    } else {
+        forms.push(Form::Expr(&graph.body));
+    }
+
+    write_program(bindings, forms)
+}
+
+/// Writes a partially evaluated tree out as a program. Every `sample` and
+/// `decision` is hoisted into a `let` binding so that copies of the same variable
+/// stay one variable.
+pub fn decompile_tree(tree: &EvaluatedTree) -> String {
+    let mut found = Vec::new();
+    hoist(tree, tree.root(), &mut found);
+    if found.iter().all(|(_, id, _)| binding_order(id).is_some()) {
+        found.sort_by_key(|(_, id, _)| binding_order(id));
+    }
+
+    let mut trees = Vec::with_capacity(found.len());
+    for (kind, id, at) in found {
+        let mut definition = EvaluatedTree::new();
+        tree.copy_into(&mut definition, at);
+        trees.push((kind, id, definition));
+    }
+    let bindings = trees.iter().map(|(kind, id, definition)| (*kind, id.clone(), definition)).collect();
+
+    write_program(bindings, vec![Form::Expr(tree)])
+}
+
+/// Writes one constraint, under its predicates, with variables under their
+/// compiled names.
+pub fn constraint_source(constraint: &Constraint) -> String {
+    let mut out = String::new();
+    write_constraint(&mut out, constraint, &Names::default());
+    out
+}
+
+enum Form<'a> {
+    Constraint(&'a Constraint),
+    Expr(&'a EvaluatedTree),
+}
+
+fn write_program(bindings: Vec<(VariableKind, Identifier, &EvaluatedTree)>, forms: Vec<Form>) -> String {
+    let mut names = Names::default();
+    for (_, id, _) in bindings.iter() {
+        names.bind(id);
+    }
+
+    let mut out = String::new();
+    let indent = if bindings.is_empty() {
+        ""
+    } else {
+        out.push_str("(let [");
+        for (i, (kind, id, definition)) in bindings.iter().enumerate() {
+            if i > 0 {
+                out.push_str("\n      ");
+            }
+            let head = match kind {
+                VariableKind::Decision => "decision",
+                VariableKind::Stochastic => "sample",
+            };
+            write!(out, "{} ({} ", names.get(id), head).unwrap();
+            write_expr(&mut out, definition, definition.root(), &names);
+            out.push(')');
+        }
+        out.push_str("]\n  ");
+        "  "
+    };
+
+    out.push_str("(begin");
+    for form in forms {
+        write!(out, "\n{}  ", indent).unwrap();
+        match form {
+            Form::Constraint(c) => write_constraint(&mut out, c, &names),
+            Form::Expr(tree) => write_expr(&mut out, tree, tree.root(), &names),
+        }
+    }
+    out.push(')');
+    if !bindings.is_empty() {
+        out.push(')');
+    }
+    out.push('\n');
+    out
+}
+
+// the source name of every variable, so that compiler-generated names like `@S3`
+// come out as valid identifiers
+#[derive(Default)]
+struct Names {
+    names: HashMap<Identifier, String>,
+}
+
+impl Names {
+    fn bind(&mut self, id: &Identifier) {
+        let mut name: String = id.trim_start_matches('@').chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            name.insert(0, 'v');
+        }
+        let base = name.clone();
+        let mut n = 1;
+        while self.names.values().any(|v| *v == name) {
+            name = format!("{}-{}", base, n);
+            n += 1;
+        }
+        self.names.insert(id.clone(), name);
+    }
+
+    fn get<'a>(&'a self, id: &'a Identifier) -> &'a str {
+        self.names.get(id).map(|n| n.as_str()).unwrap_or(id.as_str())
+    }
+}
+
+fn write_constraint(out: &mut String, constraint: &Constraint, names: &Names) {
+    for pred in constraint.predicate.iter() {
+        out.push_str("(if ");
+        write_expr(out, &pred.pred, pred.pred.root(), names);
+        // the branch the constraint isn't in is never read
+        if pred.negated {
+            out.push_str(" false ");
+        } else {
+            out.push(' ');
+        }
+    }
+
+    out.push_str("(constrain ");
+    if constraint.probability < 1. {
+        write!(out, "{} ", float(constraint.probability)).unwrap();
+    }
+    write!(out, "{} ", constraint.relation.pretty_print()).unwrap();
+    write_expr(out, &constraint.left, constraint.left.root(), names);
+    out.push(' ');
+    write_expr(out, &constraint.right, constraint.right.root(), names);
+    out.push(')');
+
+    for pred in constraint.predicate.iter().rev() {
+        if !pred.negated {
+            out.push_str(" false");
+        }
+        out.push(')');
+    }
+}
+
+fn write_expr(out: &mut String, tree: &EvaluatedTree, at: ExpressionRef, names: &Names) {
+    let write_all = |out: &mut String, head: &str, args: &[ExpressionRef]| {
+        write!(out, "({}", head).unwrap();
+        for arg in args {
+            out.push(' ');
+            write_expr(out, tree, *arg, names);
+        }
+        out.push(')');
+    };
+    match tree.deref(at) {
+        EE::C(c) => write_primitive(out, c),
+        EE::VarRef(id) => out.push_str(names.get(id)),
+        EE::Decision{id, body: _} | EE::Stochastic{id, body: _} => out.push_str(names.get(id)),
+        EE::Begin(v) => write_all(out, "begin", v),
+        EE::If{predicate, consequent, alternative} => write_all(out, "if", &[*predicate, *consequent, *alternative]),
+        EE::Constrain{prob, relation, left, right} => {
+            out.push_str("(constrain ");
+            if *prob < 1. {
+                write!(out, "{} ", float(*prob)).unwrap();
+            }
+            write!(out, "{} ", relation.pretty_print()).unwrap();
+            write_expr(out, tree, *left, names);
+            out.push(' ');
+            write_expr(out, tree, *right, names);
+            out.push(')');
+        }
+        // the value of a `constrain` form; it has no source syntax of its own
+        EE::Builtin{builtin: Builtin::Nil, args: _} => out.push_str("false"),
+        EE::Builtin{builtin, args} => write_all(out, builtin.name(), args),
+        EE::Distribution{distribution, args} => write_all(out, distribution.name(), args),
+        EE::Placeholder | EE::Deleted => unreachable!(),
+    }
+}
+
+fn write_primitive(out: &mut String, p: &Primitive) {
+    match p {
+        Primitive::Boolean(b) => write!(out, "{}", b).unwrap(),
+        Primitive::Int(i) => write!(out, "{}", i).unwrap(),
+        Primitive::Float(f) => out.push_str(&float(*f)),
+        Primitive::Vector(v) => {
+            out.push_str("(vector");
+            for x in v.iter() {
+                out.push(' ');
+                write_primitive(out, x);
+            }
+            out.push(')');
+        }
+        Primitive::EvaluatedVector(v) => write_floats(out, v.iter().map(|x| float(*x))),
+        Primitive::HashMap(m) => {
+            out.push_str("(hash-map");
+            for (k, v) in m {
+                out.push(' ');
+                write_primitive(out, k);
+                out.push(' ');
+                write_primitive(out, v);
+            }
+            out.push(')');
+        }
+        Primitive::Domain(Domain::IntRange(a, b)) => write!(out, "(int-range {} {})", a, b).unwrap(),
+        Primitive::Domain(Domain::OneOf(v)) => {
+            out.push_str("(one-of");
+            for x in v {
+                out.push(' ');
+                write_primitive(out, x);
+            }
+            out.push(')');
+        }
+        Primitive::Distribution(d) => write_distribution(out, d),
+    }
+}
+
+fn write_distribution(out: &mut String, d: &Distribution) {
+    let weights = |out: &mut String, w: &ndarray::Array1<f32>| write_floats(out, w.iter().map(|x| float32(*x)));
+    match d {
+        Distribution::Dirac{center} => write!(out, "(dirac {})", float(*center)).unwrap(),
+        Distribution::Kronecker{center} => write!(out, "(kronecker {})", center).unwrap(),
+        Distribution::UniformContinuous{a, b} => write!(out, "(uniform-continuous {} {})", float(*a), float(*b)).unwrap(),
+        Distribution::UniformDiscrete{a, b} => write!(out, "(uniform-discrete {} {})", a, b).unwrap(),
+        Distribution::Categorical{weights: w} => {
+            out.push_str("(categorical ");
+            weights(out, w);
+            out.push(')');
+        }
+        Distribution::MappedCategorical{weights: w, values} => {
+            out.push_str("(map-categorical ");
+            weights(out, w);
+            out.push(' ');
+            write_primitive(out, &Primitive::Vector(values.iter().cloned().collect()));
+            out.push(')');
+        }
+        Distribution::Normal{mu, sigma} => write!(out, "(normal {} {})", float(*mu), float(*sigma)).unwrap(),
+        Distribution::Cauchy{median, scale} => write!(out, "(cauchy {} {})", float(*median), float(*scale)).unwrap(),
+        Distribution::Beta{alpha, beta} => write!(out, "(beta {} {})", float(*alpha), float(*beta)).unwrap(),
+        Distribution::Dirichlet{weights: w} => {
+            out.push_str("(dirichlet ");
+            weights(out, w);
+            out.push(')');
+        }
+        Distribution::Exponential{lambda} => write!(out, "(exponential {})", float(*lambda)).unwrap(),
+        Distribution::Gamma{shape, rate} => write!(out, "(gamma {} {})", float(*shape), float(*rate)).unwrap(),
+        Distribution::Bernoulli{p} => write!(out, "(flip {})", float(*p)).unwrap(),
+        Distribution::Binomial{n, p} => write!(out, "(binomial {} {})", n, float(*p)).unwrap(),
+        Distribution::UniformChoice{values} => {
+            out.push_str("(uniform-choice ");
+            write_primitive(out, &Primitive::Vector(values.iter().cloned().collect()));
+            out.push(')');
+        }
+        Distribution::Poisson{lambda} => write!(out, "(poisson {})", float(*lambda)).unwrap(),
+        Distribution::Geometric{p} => write!(out, "(geometric {})", float(*p)).unwrap(),
+        Distribution::NegativeBinomial{r, p} => write!(out, "(negative-binomial {} {})", float(*r), float(*p)).unwrap(),
+        Distribution::Mixture{weights: w, components} => {
+            out.push_str("(mixture ");
+            weights(out, w);
+            out.push_str(" (vector");
+            for c in components.iter() {
+                out.push(' ');
+                write_distribution(out, c);
+            }
+            out.push_str("))");
+        }
+        Distribution::Truncated{distribution, lo, hi, mass: _} => {
+            out.push_str("(truncate ");
+            write_distribution(out, distribution);
+            write!(out, " {} {})", float(*lo), float(*hi)).unwrap();
+        }
+    }
+}
+
+fn write_floats<I: Iterator<Item = String>>(out: &mut String, xs: I) {
+    out.push_str("(vector");
+    for x in xs {
+        out.push(' ');
+        out.push_str(&x);
+    }
+    out.push(')');
+}
+
+// floats always need a `.` or an exponent to parse as floats rather than ints
+fn float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("(/ 0.0 0.0)")
+    } else if f.is_infinite() {
+        format!("(/ {} 0.0)", if f > 0. { "1.0" } else { "-1.0" })
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+fn float32(f: f32) -> String {
+    if f.is_finite() {
+        format!("{:?}", f)
+    } else {
+        float(f as f64)
+    }
+}
+
+fn hoist(tree: &EvaluatedTree, at: ExpressionRef, found: &mut Vec<(VariableKind, Identifier, ExpressionRef)>) {
+    let all = |args: &[ExpressionRef], found: &mut Vec<_>| {
+        for arg in args {
+            hoist(tree, *arg, found);
+        }
+    };
+    match tree.deref(at) {
+        EE::Decision{id, body} | EE::Stochastic{id, body} => {
+            let kind = match tree.deref(at) {
+                EE::Decision{id: _, body: _} => VariableKind::Decision,
+                _ => VariableKind::Stochastic,
+            };
+            hoist(tree, *body, found);
+            if !found.iter().any(|(_, other, _)| other == id) {
+                found.push((kind, id.clone(), *body));
+            }
+        }
+        EE::C(_) | EE::VarRef(_) | EE::Placeholder | EE::Deleted => (),
+        EE::Begin(v) => all(v, found),
+        EE::If{predicate, consequent, alternative} => all(&[*predicate, *consequent, *alternative], found),
+        EE::Constrain{prob: _, relation: _, left, right} => all(&[*left, *right], found),
+        EE::Builtin{builtin: _, args} => all(args, found),
+        EE::Distribution{distribution: _, args} => all(args, found),
+    }
+}
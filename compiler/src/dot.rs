@@ -0,0 +1,63 @@
+use common::*;
+use std::fmt::Write;
+
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn write_variable(out: &mut String, graph: &ScpGraph, var: VarRef, indent: &str) {
+    let shape = match var.kind {
+        VariableKind::Decision => "box",
+        VariableKind::Stochastic => "ellipse",
+    };
+    writeln!(out, "{}{} [shape={}];", indent, quote(graph.variables.name(var)), shape).unwrap();
+}
+
+/// Renders a graph in Graphviz DOT: decision variables are boxes, stochastic variables
+/// ellipses, dependencies arrows from parent to child and constraints diamonds joined
+/// to every variable in their scope. With `stages`, each stage gets its own cluster.
+pub fn to_dot(graph: &ScpGraph, stages: bool) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph scp {{").unwrap();
+
+    if stages {
+        for (i, stage) in graph.stages.iter().enumerate() {
+            writeln!(out, "  subgraph cluster_stage{} {{", i).unwrap();
+            writeln!(out, "    label=\"stage {}\";", i).unwrap();
+            for var in stage.decision.iter().chain(stage.stochastic.iter()) {
+                write_variable(&mut out, graph, *var, "    ");
+            }
+            writeln!(out, "  }}").unwrap();
+        }
+    } else {
+        for var in graph.variables.iter() {
+            write_variable(&mut out, graph, var, "  ");
+        }
+    }
+
+    for d in graph.dependencies.iter() {
+        for parent in d.depends_on.iter() {
+            writeln!(out, "  {} -> {};",
+                quote(graph.variables.name(*parent)),
+                quote(graph.variables.name(d.this))).unwrap();
+        }
+    }
+
+    for (i, constraint) in graph.constraints.iter().enumerate() {
+        let mut label = String::from(constraint.relation.pretty_print());
+        if constraint.probability < 1. {
+            write!(label, " (P={})", constraint.probability).unwrap();
+        }
+        if !constraint.predicate.is_empty() {
+            label.push_str(" (guarded)");
+        }
+        writeln!(out, "  c{} [shape=diamond, label={}];", i, quote(&label)).unwrap();
+        for var in constraint.scope.iter() {
+            writeln!(out, "  c{} -> {} [dir=none, style=dashed];", i, quote(graph.variables.name(*var))).unwrap();
+        }
+    }
+
+    writeln!(out, "}}").unwrap();
+    out
+}
@@ -1,21 +1,28 @@
 pub use crate::partial_eval::*;
+use crate::{linear, simplify};
 use common::*;
 
-use primitives::Primitive;
+use primitives::{Moments, Primitive};
 use smallvec::SmallVec;
 
 
 
-pub fn compile_graph(body: &EvaluatedTree) -> ScpGraph {
+#[derive(Debug)]
+pub enum GraphError {
+    // the variables along the cycle, starting and ending with the same one
+    Cycle(Vec<Identifier>),
+}
+
+pub fn compile_graph(body: &EvaluatedTree) -> Result<ScpGraph, GraphError> {
     let mut variables = Variables::new();
     gather_variables(&body, body.root(), &mut variables);
     // make_groups(&mut variables);
 
-    let mut dependencies = Vec::new();
-    gather_dependencies(&variables, &mut dependencies);
-
     let mut constraints = Vec::new();
     gather_constraints(&body, body.root(), &variables, &mut constraints, &im::Vector::new());
+    for constraint in constraints.iter_mut() {
+        simplify::simplify_constraint(constraint, &variables);
+    }
 
     // replace variable definitions with simpler versions
     // for var in variables.variables.iter_mut() {
@@ -35,8 +42,184 @@ pub fn compile_graph(body: &EvaluatedTree) -> ScpGraph {
 
     let mut new_body = EvaluatedTree::new();
     clone_refs(body, &mut new_body, body.root());
+    let new_body = simplify::simplify(&new_body, &variables);
+
+    let pruned = prune_variables(&mut variables, &constraints, &new_body);
+
+    let mut dependencies = Vec::new();
+    gather_dependencies(&variables, &mut dependencies);
+
+    let (order, children) = order_variables(&variables, &dependencies)?;
+    let stages = assign_stages(&variables);
+    for constraint in constraints.iter_mut() {
+        scope_constraint(constraint, &variables, &stages);
+    }
+
+    let linear = linear::extract_rows(&constraints, &variables);
+
+    Ok(ScpGraph{variables, dependencies, constraints, linear, order, children, stages, body: new_body, pruned})
+}
+
+// Splits the variables into stages by the order they were bound in the program: each
+// stage is a run of decisions followed by a run of stochastic variables.
+fn assign_stages(variables: &Variables) -> Vec<Stage> {
+    let mut refs: Vec<VarRef> = variables.iter().collect();
+    refs.sort_by_key(|r| binding_order(variables.name(*r)));
+
+    let mut stages = vec![Stage::default()];
+    for r in refs {
+        let stage = stages.last_mut().unwrap();
+        match r.kind {
+            VariableKind::Decision if stage.stochastic.is_empty() => stage.decision.push(r),
+            VariableKind::Decision => stages.push(Stage{decision: smallvec::smallvec![r], stochastic: SmallVec::new()}),
+            VariableKind::Stochastic => stage.stochastic.push(r),
+        }
+    }
+    stages
+}
+
+fn scope_constraint(constraint: &mut Constraint, variables: &Variables, stages: &[Stage]) {
+    let mut names = Vec::new();
+    referenced_names(&constraint.left, constraint.left.root(), &mut names);
+    referenced_names(&constraint.right, constraint.right.root(), &mut names);
+    for pred in constraint.predicate.iter() {
+        referenced_names(&pred.pred, pred.pred.root(), &mut names);
+    }
+
+    let mut scope: SmallVec<[VarRef; 8]> = names.iter().filter_map(|n| variables.get_by_name(n)).collect();
+    scope.sort_by_key(|r| r.id);
+    scope.dedup();
+
+    let stage_of = |r: &VarRef| stages.iter()
+        .position(|s| s.decision.contains(r) || s.stochastic.contains(r))
+        .unwrap_or(0);
+    constraint.stage = scope.iter().map(stage_of).max().unwrap_or(0) as u32;
+    constraint.scope = scope;
+}
+
+type Children = Vec<SmallVec<[VarRef; 8]>>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    Active,
+    Done,
+}
+
+// Orders the variables parents-first by depth-first search, failing on the first
+// cycle found. Also returns the children of each variable.
+fn order_variables(variables: &Variables, dependencies: &[Dependency]) -> Result<(Vec<VarRef>, Children), GraphError> {
+    let n = variables.variables.len();
+    let mut parents: Vec<&[VarRef]> = vec![&[]; n];
+    let mut children: Children = vec![SmallVec::new(); n];
+    for d in dependencies {
+        parents[d.this.id as usize] = &d.depends_on;
+        for p in d.depends_on.iter() {
+            children[p.id as usize].push(d.this);
+        }
+    }
+
+    let mut state = vec![Visit::New; n];
+    let mut order = Vec::with_capacity(n);
+    let mut path = Vec::new();
+    for var in variables.iter() {
+        visit(var, &parents, &mut state, &mut path, &mut order)
+            .map_err(|cycle| GraphError::Cycle(cycle.iter().map(|r| variables.name(*r).clone()).collect()))?;
+    }
+    Ok((order, children))
+}
+
+fn visit(var: VarRef, parents: &[&[VarRef]], state: &mut [Visit], path: &mut Vec<VarRef>, order: &mut Vec<VarRef>) -> Result<(), Vec<VarRef>> {
+    match state[var.id as usize] {
+        Visit::Done => return Ok(()),
+        Visit::Active => {
+            let start = path.iter().position(|r| *r == var).unwrap();
+            let mut cycle = path[start..].to_vec();
+            cycle.push(var);
+            return Err(cycle);
+        }
+        Visit::New => (),
+    }
+    state[var.id as usize] = Visit::Active;
+    path.push(var);
+    for p in parents[var.id as usize] {
+        visit(*p, parents, state, path, order)?;
+    }
+    path.pop();
+    state[var.id as usize] = Visit::Done;
+    order.push(var);
+    Ok(())
+}
+
+// Drops every variable whose value can't flow into a constraint or the body, either
+// directly or through the definition of another variable. Returns the dropped names.
+fn prune_variables(variables: &mut Variables, constraints: &[Constraint], body: &EvaluatedTree) -> Vec<Identifier> {
+    let mut dependencies = Vec::new();
+    gather_dependencies(variables, &mut dependencies);
+
+    let mut names = Vec::new();
+    for constraint in constraints {
+        referenced_names(&constraint.left, constraint.left.root(), &mut names);
+        referenced_names(&constraint.right, constraint.right.root(), &mut names);
+        for pred in constraint.predicate.iter() {
+            referenced_names(&pred.pred, pred.pred.root(), &mut names);
+        }
+    }
+    referenced_names(body, body.root(), &mut names);
+
+    let mut relevant = vec![false; variables.variables.len()];
+    let mut stack: Vec<VarRef> = names.iter().filter_map(|n| variables.get_by_name(n)).collect();
+    while let Some(var) = stack.pop() {
+        if relevant[var.id as usize] {
+            continue;
+        }
+        relevant[var.id as usize] = true;
+        if let Some(d) = dependencies.iter().find(|d| d.this == var) {
+            stack.extend(d.depends_on.iter().copied());
+        }
+    }
 
-    ScpGraph{variables, dependencies, constraints, body: new_body}
+    let mut kept = Variables::new();
+    let mut pruned = Vec::new();
+    for (var, is_relevant) in variables.variables.drain(..).zip(relevant) {
+        if is_relevant {
+            kept.push(var.kind, var.name, var.definition);
+        } else {
+            pruned.push(var.name);
+        }
+    }
+    *variables = kept;
+    pruned
+}
+
+fn referenced_names(tree: &EvaluatedTree, at: ExpressionRef, names: &mut Vec<Identifier>) {
+    match tree.deref(at) {
+        EE::C(_) | EE::Placeholder | EE::Deleted => (),
+        EE::VarRef(id) => names.push(id.clone()),
+        EE::Decision{id, body} | EE::Stochastic{id, body} => {
+            names.push(id.clone());
+            referenced_names(tree, *body, names);
+        }
+        EE::Begin(v) => {
+            for expr in v {
+                referenced_names(tree, *expr, names);
+            }
+        }
+        EE::If{predicate, consequent, alternative} => {
+            referenced_names(tree, *predicate, names);
+            referenced_names(tree, *consequent, names);
+            referenced_names(tree, *alternative, names);
+        }
+        EE::Constrain{prob: _, relation: _, left, right} => {
+            referenced_names(tree, *left, names);
+            referenced_names(tree, *right, names);
+        }
+        EE::Builtin{builtin: _, args} | EE::Distribution{distribution: _, args} => {
+            for expr in args {
+                referenced_names(tree, *expr, names);
+            }
+        }
+    }
 }
 
 fn dependency<'a>(variables: &Variables, dependencies: &'a mut Vec<Dependency>, name: &Identifier) -> Option<&'a mut Dependency> {
@@ -183,6 +366,8 @@ fn gather_variables(evald: &EvaluatedTree, at: ExpressionRef, variables: &mut Va
                 let mut new_body = EvaluatedTree::new();
                 clone_refs(evald, &mut new_body, *body);
                 variables.push(VariableKind::Decision, id.clone(), new_body);
+                // a variable read only by another's parameters appears only in there
+                gather_variables(evald, *body, variables);
             }
         }
         EE::Stochastic{id, body} => {
@@ -190,8 +375,10 @@ fn gather_variables(evald: &EvaluatedTree, at: ExpressionRef, variables: &mut Va
                 let mut new_body = EvaluatedTree::new();
                 clone_refs(evald, &mut new_body, *body);
                 variables.push(VariableKind::Stochastic, id.clone(), new_body);
+                gather_variables(evald, *body, variables);
             }
         }
+        EE::VarRef(_) => (),
         EE::If{predicate, consequent, alternative} => {
             gather_variables(evald, *predicate, variables);
             gather_variables(evald, *consequent, variables);
@@ -441,7 +628,9 @@ fn gather_constraints(tree: &EvaluatedTree, at: ExpressionRef, variables: &Varia
                 relation: *relation,
                 left: new_left,
                 right: new_right,
-                predicate: predicates.iter().cloned().collect()
+                predicate: predicates.iter().cloned().collect(),
+                scope: SmallVec::new(),
+                stage: 0,
             })
         }
         EE::Builtin{builtin: _, args} => {
@@ -459,12 +648,33 @@ fn gather_constraints(tree: &EvaluatedTree, at: ExpressionRef, variables: &Varia
 }
 
 
+// The summary statistics of a fixed distribution or domain, leaving out the ones
+// that don't exist.
+fn print_moments(m: &dyn Moments) {
+    let mut parts = Vec::new();
+    if let Some(mean) = m.mean() {
+        parts.push(format!("mean {}", mean));
+    }
+    if let Some(variance) = m.variance() {
+        parts.push(format!("variance {}", variance));
+    }
+    if let (Some(min), Some(max)) = (m.min(), m.max()) {
+        parts.push(format!("range [{}, {}]", min, max));
+    }
+    if let Some(entropy) = m.entropy() {
+        parts.push(format!("entropy {}", entropy));
+    }
+    if !parts.is_empty() {
+        println!("  → {}", parts.join(", "));
+    }
+}
+
 pub fn pretty_print(graph: &ScpGraph) {
     println!("Variables:");
     for var in graph.variables.iter() {
         let v = graph.variables.deref(var);
         println!("• {}, {:?}", &v.name, var.kind);
-        
+
         if let Some(d) = graph.dependencies_of(var) {
             print!("  → depends on:");
             for r in &d.depends_on {
@@ -476,6 +686,11 @@ pub fn pretty_print(graph: &ScpGraph) {
         
         println!("  → definition:");
         pretty_print_at(&v.definition, v.definition.root(), 2);
+        match v.definition.deref(v.definition.root()) {
+            EE::C(Primitive::Distribution(d)) => print_moments(d),
+            EE::C(Primitive::Domain(d)) => print_moments(d),
+            _ => (),
+        }
             // VariableOrGroup::Group(g) => {
             //     print!("• Group {} ( ", g.group_name);
             //     for (i, _v) in graph.variables.iter_group(var).unwrap().enumerate() {
@@ -497,9 +712,40 @@ pub fn pretty_print(graph: &ScpGraph) {
             // }
     }
 
+    println!("\nStages:");
+    for (i, stage) in graph.stages.iter().enumerate() {
+        print!("• {}: decide", i);
+        for r in stage.decision.iter() {
+            print!(" {}", graph.variables.name(*r));
+        }
+        print!(", then observe");
+        for r in stage.stochastic.iter() {
+            print!(" {}", graph.variables.name(*r));
+        }
+        println!();
+    }
+
+    print!("\nOrder:");
+    for var in graph.order.iter() {
+        print!(" {}", graph.variables.name(*var));
+    }
+    println!();
+
     println!("\nConstraints:");
-    for constraint in graph.constraints.iter() {
+    for (i, constraint) in graph.constraints.iter().enumerate() {
         println!("\n• (P={}) {}", constraint.probability, constraint.relation.pretty_print());
+        print!("  → scope:");
+        for r in constraint.scope.iter() {
+            print!(" {}", graph.variables.name(*r));
+        }
+        println!(" (determined after stage {})", constraint.stage);
+        if let Some(row) = graph.linear_row(i) {
+            print!("  → linear in:");
+            for term in row.terms.iter() {
+                print!(" {}", graph.variables.name(term.var));
+            }
+            println!("{}", if row.stochastic { " (stochastic coefficients)" } else { "" });
+        }
         println!("  → left:");
         pretty_print_at(&constraint.left, constraint.left.root(), 2);
         println!("  → right:");
@@ -522,6 +768,14 @@ pub fn pretty_print(graph: &ScpGraph) {
         }
     }
 
+    if !graph.pruned.is_empty() {
+        print!("\nPruned (cannot affect constraints or body):");
+        for name in graph.pruned.iter() {
+            print!(" {}", name);
+        }
+        println!();
+    }
+
     println!("\nBody:");
     pretty_print_at(&graph.body, graph.body.root(), 1);
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use crate::partial_eval::EE;
+use crate::simplify::{self, Atom, Number, Poly, Term};
+use common::*;
+use std::collections::BTreeMap;
+
+
+/// Finds every constraint that is linear in the decision variables once the
+/// stochastic variables are fixed, and rewrites it as a sparse `LinearRow`.
+pub fn extract_rows(constraints: &[Constraint], variables: &Variables) -> Vec<LinearRow> {
+    let mut rows = Vec::new();
+    for (i, constraint) in constraints.iter().enumerate() {
+        if let Some(row) = linear_row(i as u32, constraint, variables) {
+            rows.push(row);
+        }
+    }
+    rows
+}
+
+fn linear_row(index: u32, constraint: &Constraint, variables: &Variables) -> Option<LinearRow> {
+    let difference = simplify::poly(&constraint.left, constraint.left.root(), variables)
+        .sub(simplify::poly(&constraint.right, constraint.right.root(), variables));
+    let (terms, rhs, stochastic) = linear_form(&difference, variables)?;
+    if terms.is_empty() {
+        return None;
+    }
+    Some(LinearRow{
+        constraint: index,
+        relation: constraint.relation,
+        terms,
+        rhs,
+        stochastic,
+    })
+}
+
+/// Splits a polynomial into `Σ coefficient·decision - rhs`, where each coefficient
+/// and the rhs may only read stochastic variables. Returns `None` if the polynomial
+/// isn't linear in the decision variables.
+pub(crate) fn linear_form(difference: &Poly, variables: &Variables) -> Option<(Vec<LinearTerm>, EvaluatedTree, bool)> {
+    // decision variable id -> its coefficient, as a polynomial over stochastic variables
+    let mut coefficients: BTreeMap<u32, Poly> = BTreeMap::new();
+    let mut rest = Poly::default();
+    let mut stochastic = false;
+
+    for term in difference.terms.values() {
+        let mut decision: Option<VarRef> = None;
+        let mut factors = Vec::with_capacity(term.factors.len());
+        for (key, atom, power) in term.factors.iter() {
+            match atom {
+                Atom::Var(id) => {
+                    let r = variables.get_by_name(id)?;
+                    match r.kind {
+                        VariableKind::Decision => {
+                            if decision.is_some() || *power != 1 || !simplify::is_numeric_variable(variables.deref(r)) {
+                                return None;
+                            }
+                            decision = Some(r);
+                            continue;
+                        }
+                        VariableKind::Stochastic => stochastic = true,
+                    }
+                }
+                Atom::Opaque(tree) => {
+                    if mentions_decision(tree, tree.root(), variables) {
+                        return None;
+                    }
+                    stochastic = true;
+                }
+            }
+            factors.push((key.clone(), atom.clone(), *power));
+        }
+
+        let coefficient = Term{factors, coefficient: term.coefficient};
+        match decision {
+            Some(r) => coefficients.entry(r.id).or_default().push(coefficient),
+            None => rest.push(coefficient),
+        }
+    }
+
+    let terms = coefficients.into_iter()
+        .map(|(id, coefficient)| LinearTerm{
+            var: VarRef{kind: VariableKind::Decision, id},
+            coefficient: simplify::emit_root(&coefficient),
+        })
+        .collect();
+
+    Some((terms, simplify::emit_root(&rest.scale(Number::Int(-1))), stochastic))
+}
+
+fn mentions_decision(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables) -> bool {
+    let any = |args: &[ExpressionRef]| args.iter().any(|arg| mentions_decision(tree, *arg, variables));
+    match tree.deref(at) {
+        EE::VarRef(id) => match variables.get_by_name(id) {
+            Some(r) => r.kind == VariableKind::Decision,
+            None => true,
+        },
+        EE::Decision{id: _, body: _} => true,
+        EE::Stochastic{id: _, body: _} => false,
+        EE::C(_) | EE::Placeholder | EE::Deleted => false,
+        EE::Begin(v) => any(v),
+        EE::If{predicate, consequent, alternative} => any(&[*predicate, *consequent, *alternative]),
+        EE::Constrain{prob: _, relation: _, left, right} => any(&[*left, *right]),
+        EE::Builtin{builtin: _, args} => any(args),
+        EE::Distribution{distribution: _, args} => any(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompile::decompile_tree;
+
+    // a row as (constraint, stochastic, rhs, [(decision id, coefficient)])
+    type Row = (u32, bool, String, Vec<(u32, String)>);
+
+    fn rows(program: &str) -> Vec<Row> {
+        let source = |tree: &EvaluatedTree| decompile_tree(tree).trim_start_matches("(begin\n  ").trim_end_matches(")\n").to_string();
+        crate::compile(program).linear.iter()
+            .map(|row| (
+                row.constraint,
+                row.stochastic,
+                source(&row.rhs),
+                row.terms.iter().map(|term| (term.var.id, source(&term.coefficient))).collect(),
+            ))
+            .collect()
+    }
+
+    const PROGRAM: &str = "(let [d (sample (map-categorical (vector 1 1) (vector 1 4)))
+                                 x (decision (int-range 0 5))
+                                 y (decision (int-range 0 5))]";
+
+    #[test]
+    fn stochastic_coefficients() {
+        let program = format!("{} (begin (constrain >= (+ (* d x) y) (* 2 d)) (constrain <= (+ x y) 3)))", PROGRAM);
+        assert_eq!(rows(&program), [
+            (0, true, String::from("(* 2 @S0)"), vec![(1, String::from("@S0")), (2, String::from("1"))]),
+            (1, false, String::from("3"), vec![(1, String::from("1")), (2, String::from("1"))]),
+        ]);
+    }
+
+    #[test]
+    fn guarded_coefficients() {
+        let program = format!("{} (begin
+            (constrain >= (* (if (>? d 2) 3 1) x) 4)
+            (constrain <= (if (>? d 2) x y) 3)))", PROGRAM);
+        // a guard may pick a coefficient, but not which decision is constrained
+        assert_eq!(rows(&program), [
+            (0, true, String::from("4"), vec![(1, String::from("(if (>? @S0 2) 3 1)"))]),
+        ]);
+    }
+
+    #[test]
+    fn nonlinear_constraints_have_no_row() {
+        let program = format!("{} (begin (constrain >= (* x y) 1) (constrain <= (* x x) 4) (constrain >= d 1)))", PROGRAM);
+        assert!(rows(&program).is_empty());
+    }
+}
@@ -0,0 +1,540 @@
+use crate::decompile::constraint_source;
+use crate::linear;
+use crate::simplify;
+use common::*;
+use common::scenario::{self, ScenarioTree};
+use primitives::{Domain, Primitive};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+
+#[derive(Debug)]
+pub enum LpError {
+    // the constraint isn't linear in the decision variables
+    NonLinear(String),
+    Unsupported(String),
+    // a constraint with no decisions in it fails in some scenario
+    Infeasible(String),
+}
+
+impl std::fmt::Display for LpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NonLinear(c) => write!(f, "{} is not linear in the decision variables", c),
+            Self::Unsupported(e) => write!(f, "{}", e),
+            Self::Infeasible(c) => write!(f, "{} fails whatever the decisions are", c),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Integer,
+    Binary,
+    Continuous,
+}
+
+struct Column {
+    name: String,
+    kind: Kind,
+    lo: f64,
+    hi: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Sense {
+    Le,
+    Ge,
+    Eq,
+}
+
+struct Row {
+    name: String,
+    terms: Vec<(usize, f64)>,
+    sense: Sense,
+    rhs: f64,
+}
+
+// a mixed-integer program in the form both writers share
+struct Model {
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+    objective: Vec<(usize, f64)>,
+    // the part of the objective that doesn't depend on any decision
+    constant: f64,
+    scenarios: usize,
+}
+
+impl Model {
+    fn column(&mut self, name: String, kind: Kind, lo: f64, hi: f64) -> usize {
+        self.columns.push(Column{name, kind, lo, hi});
+        self.columns.len() - 1
+    }
+}
+
+fn ident(name: &str) -> String {
+    let mut s: String = name.trim_start_matches('@').chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        s.insert(0, 'v');
+    }
+    s
+}
+
+fn number(p: &Primitive) -> Option<f64> {
+    match p {
+        Primitive::Int(i) => Some(*i as f64),
+        Primitive::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn value(tree: &EvaluatedTree, values: &HashMap<Identifier, Primitive>) -> Result<f64, LpError> {
+    let v = scenario::eval(tree, tree.root(), values).map_err(LpError::Unsupported)?;
+    number(&v).ok_or_else(|| LpError::Unsupported(format!("{:?} is not a number", v)))
+}
+
+// one column per copy of each decision; returns the column of every (decision, node)
+fn decision_columns(graph: &ScpGraph, tree: &ScenarioTree, depths: &[usize], model: &mut Model) -> Result<HashMap<(u32, usize), usize>, LpError> {
+    let mut columns = HashMap::new();
+    for var in graph.variables.iter().filter(|r| r.kind == VariableKind::Decision) {
+        let name = graph.variables.name(var);
+        let definition = &graph.variables.deref(var).definition;
+        let depth = depths[var.id as usize];
+        for node in (0..tree.nodes.len()).filter(|node| tree.nodes[*node].depth == depth) {
+            let values = tree.assignment(node, &graph.variables);
+            let domain = scenario::eval(definition, definition.root(), &values)
+                .map_err(|e| LpError::Unsupported(format!("could not evaluate the domain of {}: {}", name, e)))?;
+            let copy = format!("{}_{}", ident(name), node);
+
+            let column = match domain {
+                Primitive::Domain(Domain::IntRange(a, b)) if b > a => {
+                    model.column(copy, Kind::Integer, a as f64, (b - 1) as f64)
+                }
+                Primitive::Domain(Domain::OneOf(choices)) => one_of(copy, &choices, model)?,
+                other => return Err(LpError::Unsupported(format!("decision domain {:?} of {} has no LP form", other, name))),
+            };
+            columns.insert((var.id, node), column);
+        }
+    }
+    Ok(columns)
+}
+
+// a decision over a finite set: contiguous integers are a bounded integer column,
+// anything else picks exactly one value with a binary per choice
+fn one_of(copy: String, choices: &[Primitive], model: &mut Model) -> Result<usize, LpError> {
+    if choices.iter().all(|c| matches!(c, Primitive::Boolean(_))) {
+        return Ok(model.column(copy, Kind::Binary, 0., 1.));
+    }
+    let mut values = Vec::with_capacity(choices.len());
+    for c in choices {
+        values.push(number(c).ok_or_else(|| LpError::Unsupported(format!("decision value {:?} has no LP form", c)))?);
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    values.dedup();
+
+    let integral = choices.iter().all(|c| matches!(c, Primitive::Int(_)));
+    let (lo, hi) = (values[0], values[values.len() - 1]);
+    if integral && hi - lo + 1. == values.len() as f64 {
+        return Ok(model.column(copy, Kind::Integer, lo, hi));
+    }
+
+    let kind = if integral { Kind::Integer } else { Kind::Continuous };
+    let x = model.column(copy.clone(), kind, lo, hi);
+    let mut pick = Vec::with_capacity(values.len());
+    let mut link = vec![(x, 1.)];
+    for (k, v) in values.iter().enumerate() {
+        let z = model.column(format!("{}_is{}", copy, k), Kind::Binary, 0., 1.);
+        pick.push((z, 1.));
+        if *v != 0. {
+            link.push((z, -v));
+        }
+    }
+    model.rows.push(Row{name: format!("{}_pick", copy), terms: pick, sense: Sense::Eq, rhs: 1.});
+    model.rows.push(Row{name: format!("{}_value", copy), terms: link, sense: Sense::Eq, rhs: 0.});
+    Ok(x)
+}
+
+fn sense(relation: Relation) -> Option<Sense> {
+    match relation {
+        Relation::Leq => Some(Sense::Le),
+        Relation::Geq => Some(Sense::Ge),
+        Relation::Eq => Some(Sense::Eq),
+        _ => None,
+    }
+}
+
+// whether the predicates guarding a constraint hold in a scenario
+fn guarded(c: &Constraint, values: &HashMap<Identifier, Primitive>) -> Result<bool, LpError> {
+    for pred in c.predicate.iter() {
+        let p = match scenario::eval(&pred.pred, pred.pred.root(), values) {
+            Ok(Primitive::Boolean(b)) => b,
+            Ok(other) => number(&other).map(|f| f != 0.).unwrap_or(false),
+            Err(_) => return Err(LpError::NonLinear(format!("{}, whose condition reads a decision,", constraint_source(c)))),
+        };
+        if p == pred.negated {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// the most a row's left side can exceed (`Le`) or fall short of (`Ge`) its rhs,
+// given the bounds of its columns
+fn big_m(row: &Row, columns: &[Column], sense: Sense) -> f64 {
+    let mut extreme = 0.;
+    for (col, a) in row.terms.iter() {
+        let (lo, hi) = (a * columns[*col].lo, a * columns[*col].hi);
+        extreme += match sense {
+            Sense::Ge => lo.min(hi),
+            _ => lo.max(hi),
+        };
+    }
+    let m = match sense {
+        Sense::Ge => row.rhs - extreme,
+        _ => extreme - row.rhs,
+    };
+    m.max(0.)
+}
+
+fn build(graph: &ScpGraph, tree: &ScenarioTree) -> Result<Model, LpError> {
+    let leaves: Vec<usize> = tree.leaves().collect();
+    let depths = scenario::decision_depths(graph);
+    let mut model = Model{columns: Vec::new(), rows: Vec::new(), objective: Vec::new(), constant: 0., scenarios: leaves.len()};
+    let columns = decision_columns(graph, tree, &depths, &mut model)?;
+
+    let assignments: Vec<HashMap<Identifier, Primitive>> = leaves.iter()
+        .map(|leaf| tree.assignment(*leaf, &graph.variables))
+        .collect();
+    let copy = |var: VarRef, leaf: usize| columns[&(var.id, tree.ancestor(leaf, depths[var.id as usize]))];
+
+    for (i, c) in graph.constraints.iter().enumerate() {
+        let row = graph.linear_row(i);
+        let has_decision = c.scope.iter().any(|r| r.kind == VariableKind::Decision);
+        if row.is_none() && has_decision {
+            return Err(LpError::NonLinear(constraint_source(c)));
+        }
+
+        let mut rows: Vec<(usize, Row)> = Vec::new();
+        // probability of the scenarios in which the constraint fails outright
+        let mut failed = 0.;
+        for (leaf, values) in leaves.iter().zip(assignments.iter()) {
+            if !guarded(c, values)? {
+                continue;
+            }
+            let p = tree.nodes[*leaf].probability;
+
+            let row = match row {
+                Some(row) => row,
+                None => {
+                    let l = value(&c.left, values)?;
+                    let r = value(&c.right, values)?;
+                    let holds = match c.relation {
+                        Relation::Eq => l == r,
+                        Relation::Neq => l != r,
+                        Relation::Lt => l < r,
+                        Relation::Gt => l > r,
+                        Relation::Leq => l <= r,
+                        Relation::Geq => l >= r,
+                    };
+                    if !holds {
+                        failed += p;
+                    }
+                    continue;
+                }
+            };
+
+            let mut terms = Vec::with_capacity(row.terms.len());
+            let mut integral = true;
+            for term in row.terms.iter() {
+                let a = value(&term.coefficient, values)?;
+                // a coefficient can vanish in one scenario, as the simplifier
+                // drops terms that vanish in all of them
+                if a == 0. {
+                    continue;
+                }
+                let col = copy(term.var, *leaf);
+                integral &= a.fract() == 0. && model.columns[col].kind != Kind::Continuous;
+                terms.push((col, a));
+            }
+            let mut rhs = value(&row.rhs, values)?;
+            integral &= rhs.fract() == 0.;
+
+            // a strict inequality over integers is the non-strict one a step inside
+            let sense = match (row.relation, integral) {
+                (Relation::Lt, true) => { rhs -= 1.; Sense::Le }
+                (Relation::Gt, true) => { rhs += 1.; Sense::Ge }
+                (relation, _) => sense(relation).ok_or_else(|| LpError::Unsupported(format!(
+                    "{} uses {} between non-integral sides, which has no LP form", constraint_source(c), relation.pretty_print())))?,
+            };
+            rows.push((*leaf, Row{name: format!("c{}_s{}", i, leaf), terms, sense, rhs}));
+        }
+
+        if c.probability >= 1. {
+            if failed > 0. {
+                return Err(LpError::Infeasible(constraint_source(c)));
+            }
+            for (_, row) in rows {
+                let duplicate = model.rows.iter().any(|r| r.terms == row.terms && r.sense == row.sense && r.rhs == row.rhs);
+                if !duplicate {
+                    model.rows.push(row);
+                }
+            }
+            continue;
+        }
+
+        // a chance constraint: in each scenario a binary lets the row be violated,
+        // by at most big M, and the violating scenarios' probability is bounded
+        let mut budget = Vec::with_capacity(rows.len());
+        for (leaf, row) in rows {
+            let y = model.column(format!("y{}_s{}", i, leaf), Kind::Binary, 0., 1.);
+            budget.push((y, tree.nodes[leaf].probability));
+            let senses: &[Sense] = match row.sense {
+                Sense::Eq => &[Sense::Le, Sense::Ge],
+                Sense::Le => &[Sense::Le],
+                Sense::Ge => &[Sense::Ge],
+            };
+            for s in senses {
+                let m = big_m(&row, &model.columns, *s);
+                let mut terms = row.terms.clone();
+                if m != 0. {
+                    terms.push((y, if *s == Sense::Ge { m } else { -m }));
+                }
+                let name = if row.sense == Sense::Eq {
+                    format!("{}_{}", row.name, if *s == Sense::Ge { "ge" } else { "le" })
+                } else {
+                    row.name.clone()
+                };
+                model.rows.push(Row{name, terms, sense: *s, rhs: row.rhs});
+            }
+        }
+        model.rows.push(Row{name: format!("chance{}", i), terms: budget, sense: Sense::Le, rhs: 1. - c.probability - failed});
+    }
+
+    // the body, weighted by scenario probability, is the objective to minimise
+    if !graph.body.is_nil() {
+        let body = simplify::poly(&graph.body, graph.body.root(), &graph.variables);
+        let (terms, rest, _) = linear::linear_form(&body, &graph.variables)
+            .ok_or_else(|| LpError::NonLinear(String::from("the body")))?;
+        let mut objective: BTreeMap<usize, f64> = BTreeMap::new();
+        for (leaf, values) in leaves.iter().zip(assignments.iter()) {
+            let p = tree.nodes[*leaf].probability;
+            for term in terms.iter() {
+                *objective.entry(copy(term.var, *leaf)).or_insert(0.) += p * value(&term.coefficient, values)?;
+            }
+            model.constant -= p * value(&rest, values)?;
+        }
+        model.objective = objective.into_iter().filter(|(_, a)| *a != 0.).collect();
+    }
+
+    Ok(model)
+}
+
+fn float(f: f64) -> String {
+    format!("{}", f)
+}
+
+fn write_lp(model: &Model) -> String {
+    let mut out = String::new();
+    writeln!(out, "\\ deterministic equivalent over {} scenarios", model.scenarios).unwrap();
+    if model.constant != 0. {
+        writeln!(out, "\\ the objective also has a constant term of {}", float(model.constant)).unwrap();
+    }
+
+    let sum = |terms: &[(usize, f64)]| -> String {
+        let mut s = String::new();
+        for (i, (col, a)) in terms.iter().enumerate() {
+            let sign = if *a < 0. { "-" } else if i > 0 { "+" } else { "" };
+            write!(s, " {} {} {}", sign, float(a.abs()), model.columns[*col].name).unwrap();
+        }
+        if terms.is_empty() && !model.columns.is_empty() {
+            write!(s, " 0 {}", model.columns[0].name).unwrap();
+        }
+        s
+    };
+
+    writeln!(out, "Minimize\n obj:{}", sum(&model.objective)).unwrap();
+    writeln!(out, "Subject To").unwrap();
+    for row in model.rows.iter() {
+        let sense = match row.sense {
+            Sense::Le => "<=",
+            Sense::Ge => ">=",
+            Sense::Eq => "=",
+        };
+        writeln!(out, " {}:{} {} {}", row.name, sum(&row.terms), sense, float(row.rhs)).unwrap();
+    }
+
+    writeln!(out, "Bounds").unwrap();
+    for col in model.columns.iter().filter(|c| c.kind != Kind::Binary) {
+        writeln!(out, " {} <= {} <= {}", float(col.lo), col.name, float(col.hi)).unwrap();
+    }
+    for (section, kind) in [("General", Kind::Integer), ("Binary", Kind::Binary)].iter() {
+        let names: Vec<&str> = model.columns.iter().filter(|c| c.kind == *kind).map(|c| c.name.as_str()).collect();
+        if !names.is_empty() {
+            writeln!(out, "{}\n {}", section, names.join(" ")).unwrap();
+        }
+    }
+    writeln!(out, "End").unwrap();
+    out
+}
+
+fn write_mps(model: &Model) -> String {
+    let mut out = String::new();
+    writeln!(out, "* deterministic equivalent over {} scenarios", model.scenarios).unwrap();
+    writeln!(out, "NAME scp").unwrap();
+    writeln!(out, "ROWS").unwrap();
+    writeln!(out, " N obj").unwrap();
+    for row in model.rows.iter() {
+        let sense = match row.sense {
+            Sense::Le => "L",
+            Sense::Ge => "G",
+            Sense::Eq => "E",
+        };
+        writeln!(out, " {} {}", sense, row.name).unwrap();
+    }
+
+    // every column's entries, in row order, with the objective first
+    let mut entries: Vec<Vec<(&str, f64)>> = vec![Vec::new(); model.columns.len()];
+    for (col, a) in model.objective.iter() {
+        entries[*col].push(("obj", *a));
+    }
+    for row in model.rows.iter() {
+        for (col, a) in row.terms.iter() {
+            entries[*col].push((row.name.as_str(), *a));
+        }
+    }
+
+    writeln!(out, "COLUMNS").unwrap();
+    let mut integer = false;
+    for (col, column) in model.columns.iter().enumerate() {
+        let is_integer = column.kind != Kind::Continuous;
+        if is_integer != integer {
+            let marker = if is_integer { "INTORG" } else { "INTEND" };
+            writeln!(out, " MARKER 'MARKER' '{}'", marker).unwrap();
+            integer = is_integer;
+        }
+        for (row, a) in entries[col].iter() {
+            writeln!(out, " {} {} {}", column.name, row, float(*a)).unwrap();
+        }
+        if entries[col].is_empty() {
+            writeln!(out, " {} obj 0", column.name).unwrap();
+        }
+    }
+    if integer {
+        writeln!(out, " MARKER 'MARKER' 'INTEND'").unwrap();
+    }
+
+    writeln!(out, "RHS").unwrap();
+    if model.constant != 0. {
+        writeln!(out, " RHS obj {}", float(-model.constant)).unwrap();
+    }
+    for row in model.rows.iter().filter(|r| r.rhs != 0.) {
+        writeln!(out, " RHS {} {}", row.name, float(row.rhs)).unwrap();
+    }
+
+    writeln!(out, "BOUNDS").unwrap();
+    for col in model.columns.iter() {
+        match col.kind {
+            Kind::Binary => writeln!(out, " BV BND {}", col.name).unwrap(),
+            _ => {
+                writeln!(out, " LO BND {} {}", col.name, float(col.lo)).unwrap();
+                writeln!(out, " UP BND {} {}", col.name, float(col.hi)).unwrap();
+            }
+        }
+    }
+    writeln!(out, "ENDATA").unwrap();
+    out
+}
+
+/// Writes the deterministic equivalent of a linear graph in CPLEX LP format. Each
+/// decision has one column per outcome of the stochastic variables observed before
+/// it, each constraint one row per scenario, and each chance constraint a big-M
+/// indicator per scenario under a budget of `1 - probability`. The body, weighted by
+/// scenario probability, is minimised. The scenarios are the leaves of `tree`.
+pub fn to_lp(graph: &ScpGraph, tree: &ScenarioTree) -> Result<String, LpError> {
+    Ok(write_lp(&build(graph, tree)?))
+}
+
+/// The same model as `to_lp`, in free MPS format.
+pub fn to_mps(graph: &ScpGraph, tree: &ScenarioTree) -> Result<String, LpError> {
+    Ok(write_mps(&build(graph, tree)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a one-of decision with a zero choice, that has to cover both scenarios
+    const ONE_OF: &str = "(let [x (decision (one-of 0 2 5))
+                                d (sample (map-categorical (vector 1 1) (vector 1 4)))]
+                            (begin
+                              (constrain >= x d)
+                              x))";
+
+    fn model() -> (ScpGraph, ScenarioTree) {
+        let graph = crate::compile(ONE_OF);
+        let tree = scenario::build(&graph).unwrap();
+        (graph, tree)
+    }
+
+    #[test]
+    fn lp_golden() {
+        let (graph, tree) = model();
+        assert_eq!(to_lp(&graph, &tree).unwrap(), "\
+\\ deterministic equivalent over 2 scenarios
+Minimize
+ obj:  1 D0_0
+Subject To
+ D0_0_pick:  1 D0_0_is0 + 1 D0_0_is1 + 1 D0_0_is2 = 1
+ D0_0_value:  1 D0_0 - 2 D0_0_is1 - 5 D0_0_is2 = 0
+ c0_s1:  1 D0_0 >= 1
+ c0_s2:  1 D0_0 >= 4
+Bounds
+ 0 <= D0_0 <= 5
+General
+ D0_0
+Binary
+ D0_0_is0 D0_0_is1 D0_0_is2
+End
+");
+    }
+
+    #[test]
+    fn mps_golden() {
+        let (graph, tree) = model();
+        assert_eq!(to_mps(&graph, &tree).unwrap(), "\
+* deterministic equivalent over 2 scenarios
+NAME scp
+ROWS
+ N obj
+ E D0_0_pick
+ E D0_0_value
+ G c0_s1
+ G c0_s2
+COLUMNS
+ MARKER 'MARKER' 'INTORG'
+ D0_0 obj 1
+ D0_0 D0_0_value 1
+ D0_0 c0_s1 1
+ D0_0 c0_s2 1
+ D0_0_is0 D0_0_pick 1
+ D0_0_is1 D0_0_pick 1
+ D0_0_is1 D0_0_value -2
+ D0_0_is2 D0_0_pick 1
+ D0_0_is2 D0_0_value -5
+ MARKER 'MARKER' 'INTEND'
+RHS
+ RHS D0_0_pick 1
+ RHS c0_s1 1
+ RHS c0_s2 4
+BOUNDS
+ LO BND D0_0 0
+ UP BND D0_0 5
+ BV BND D0_0_is0
+ BV BND D0_0_is1
+ BV BND D0_0_is2
+ENDATA
+");
+    }
+}
@@ -4,6 +4,13 @@ pub mod parser;
 pub mod desugar;
 pub mod partial_eval;
 pub mod graph;
+pub mod simplify;
+pub mod linear;
+pub mod dot;
+pub mod decompile;
+pub mod minizinc;
+pub mod lp;
+pub mod smps;
 
 use nom::error::VerboseError;
 use std::path;
@@ -20,33 +27,186 @@ fn prettyprint<'a, T : std::fmt::Debug>(i: &'static str, val: nom::IResult<&'a s
 }
 
 
+struct Options {
+    input: path::PathBuf,
+    output: path::PathBuf,
+    dot: Option<path::PathBuf>,
+    dot_stages: bool,
+    json: bool,
+    decompile: Option<path::PathBuf>,
+    minizinc: Option<path::PathBuf>,
+    lp: Option<path::PathBuf>,
+    mps: Option<path::PathBuf>,
+    stats: Option<path::PathBuf>,
+    smps: bool,
+    smps_options: smps::ImportOptions,
+    // branching factors of a sampled scenario tree; the full tree if `None`
+    saa: Option<Vec<usize>>,
+    saa_sampling: common::saa::Sampling,
+    saa_gap: Option<usize>,
+    seed: common::random::Seed,
+    solve: bool,
+    discretize: Option<common::discretize::Discretization>,
+    discretize_vars: Vec<(common::Identifier, common::discretize::Discretization)>,
+}
 
-pub fn main() {
-    use std::time::Instant;
-    use std::io::Write;
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut dot = None;
+    let mut dot_stages = false;
+    let mut json = false;
+    let mut decompile = None;
+    let mut minizinc = None;
+    let mut lp = None;
+    let mut mps = None;
+    let mut stats = None;
+    let mut smps = false;
+    let mut smps_options = smps::ImportOptions::default();
+    let mut saa = None;
+    let mut saa_sampling = common::saa::Sampling::Iid;
+    let mut saa_gap = None;
+    let mut seed = None;
+    let mut solve = false;
+    let mut discretize = None;
+    let mut discretize_vars = Vec::new();
 
-    let now = || Instant::now();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dot" => {
+                let p = iter.next().ok_or("--dot requires an output path")?;
+                dot = Some(path::PathBuf::from(p));
+            }
+            "--dot-stages" => dot_stages = true,
+            "--json" => json = true,
+            "--decompile" => {
+                let p = iter.next().ok_or("--decompile requires an output path")?;
+                decompile = Some(path::PathBuf::from(p));
+            }
+            "--minizinc" => {
+                let p = iter.next().ok_or("--minizinc requires an output path")?;
+                minizinc = Some(path::PathBuf::from(p));
+            }
+            "--lp" => {
+                let p = iter.next().ok_or("--lp requires an output path")?;
+                lp = Some(path::PathBuf::from(p));
+            }
+            "--mps" => {
+                let p = iter.next().ok_or("--mps requires an output path")?;
+                mps = Some(path::PathBuf::from(p));
+            }
+            "--stats" => {
+                let p = iter.next().ok_or("--stats requires an output path")?;
+                stats = Some(path::PathBuf::from(p));
+            }
+            "--smps" => smps = true,
+            "--smps-grid" => {
+                let n = iter.next().ok_or("--smps-grid requires a number of values")?;
+                smps_options.grid = n.parse().map_err(|_| format!("Invalid grid size {}", n))?;
+            }
+            "--smps-bound" => {
+                let b = iter.next().ok_or("--smps-bound requires a bound")?;
+                smps_options.bound = Some(b.parse().map_err(|_| format!("Invalid bound {}", b))?);
+            }
+            "--saa" => {
+                let b = iter.next().ok_or("--saa requires branching factors")?;
+                let factors = b.split(',')
+                    .map(|f| f.trim().parse::<usize>().ok().filter(|f| *f > 0))
+                    .collect::<Option<Vec<usize>>>()
+                    .ok_or_else(|| format!("Invalid branching factors {}", b))?;
+                saa = Some(factors);
+            }
+            "--saa-lhs" => saa_sampling = common::saa::Sampling::LatinHypercube,
+            "--saa-gap" => {
+                let m = iter.next().ok_or("--saa-gap requires a number of replications")?;
+                saa_gap = Some(m.parse().ok().filter(|m| *m >= 2).ok_or_else(|| format!("Invalid number of replications {}", m))?);
+            }
+            "--seed" => {
+                let n = iter.next().ok_or("--seed requires a number")?;
+                seed = Some(n.parse().map_err(|_| format!("Invalid seed {}", n))?);
+            }
+            "--solve" => solve = true,
+            "--discretize" => {
+                let m = iter.next().ok_or("--discretize requires a method")?;
+                discretize = Some(m.parse()?);
+            }
+            "--discretize-var" => {
+                let v = iter.next().ok_or("--discretize-var requires <variable>=<method>")?;
+                let (name, m) = v.split_once('=').ok_or_else(|| format!("Invalid --discretize-var {}, expected <variable>=<method>", v))?;
+                discretize_vars.push((common::Identifier::from(name), m.parse()?));
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
 
-    let args: Vec<String> = std::env::args().collect();
+    if saa_gap.is_some() && saa.is_none() {
+        return Err(String::from("--saa-gap requires --saa"));
+    }
 
-    if args.len() != 3 {
-        eprintln!("Wrong number of arguments, expected 2, got {}", args.len() - 1);
-        std::process::exit(1);
+    if positional.len() != 2 {
+        return Err(format!("Wrong number of arguments, expected 2, got {}", positional.len()));
     }
 
-    let fpath = path::PathBuf::from(&args[1]);
-    let opath = path::PathBuf::from(&args[2]);
+    Ok(Options {
+        input: path::PathBuf::from(positional[0]),
+        output: path::PathBuf::from(positional[1]),
+        dot,
+        dot_stages,
+        json,
+        decompile,
+        minizinc,
+        lp,
+        mps,
+        stats,
+        smps,
+        smps_options,
+        saa,
+        saa_sampling,
+        saa_gap,
+        seed: common::random::Seed::new(seed),
+        solve,
+        discretize,
+        discretize_vars,
+    })
+}
 
-    let program = std::fs::read_to_string(fpath).expect(&format!("Could not find file {:?}", &args[1]));
+fn write_export<E: std::fmt::Display>(p: &path::Path, format: &str, model: Result<String, E>, code: i32) {
+    match model {
+        Ok(model) => {
+            println!("Writing {} model to {:?}…", format, p);
+            std::fs::write(p, model).unwrap();
+        }
+        Err(e) => {
+            eprintln!("Could not export to {}: {}", format, e);
+            std::process::exit(code);
+        }
+    }
+}
+
+// `compile_source` without the printing, for tests.
+#[cfg(test)]
+pub(crate) fn compile(program: &str) -> common::ScpGraph {
+    let (_, parsed) = parser::parse_program(program).expect("program parses");
+    let desugared = desugar::desugar(&parsed).expect("program desugars");
+    let Ok(evald) = partial_eval::partial_eval(&desugared.body) else {
+        panic!("program does not evaluate")
+    };
+    graph::compile_graph(&evald).expect("program compiles")
+}
+
+// Parses, desugars, partially evaluates and compiles a program, printing each step.
+fn compile_source(program: &str) -> common::ScpGraph {
+    let now = || std::time::Instant::now();
 
     let t0 = now();
 
-    let parsed = match parser::parse_program(&program) {
+    let parsed = match parser::parse_program(program) {
         Ok((_, p)) => p,
         Err(e) => match e {
             nom::Err::Failure(e) => {
                 eprintln!("Parsing error:");
-                eprintln!("{}", nom::error::convert_error(program.as_str(), e));
+                eprintln!("{}", nom::error::convert_error(program, e));
                 std::process::exit(2);
             },
             _ => unreachable!()
@@ -107,20 +267,225 @@ pub fn main() {
 
     
     let t5 = now();
-    let g = graph::compile_graph(&evald);
+    let g = match graph::compile_graph(&evald) {
+        Ok(g) => g,
+        Err(e) => {
+            eprint!("Error while compiling graph: ");
+            match e {
+                graph::GraphError::Cycle(names) => {
+                    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+                    eprintln!("dependency cycle {}", names.join(" → "));
+                }
+            }
+            std::process::exit(5);
+        }
+    };
     let t6 = now();
 
-    println!("\n==============\n    Graph:\n==============\n");
-    graph::pretty_print(&g);
-
     println!("\nParsing took            {:?}", t1.duration_since(t0));
     println!("Desugaring took         {:?}", t2.duration_since(t1));
     println!("Partial evaluation took {:?}", t4.duration_since(t3));
     println!("Graph compilation took  {:?}", t6.duration_since(t5));
 
+    g
+}
+
+// Reads an SMPS triple: `input` is the core file, with the time and stoch files
+// next to it under the same name. Returns the graph and the three files, which
+// stand in for the source in the output container.
+fn import_smps(input: &path::Path, options: &smps::ImportOptions) -> (common::ScpGraph, String) {
+    let find = |extensions: &[&str]| {
+        extensions.iter()
+            .map(|e| input.with_extension(e))
+            .find(|p| p.exists())
+            .unwrap_or_else(|| {
+                eprintln!("Could not find a .{} file next to {:?}", extensions[0], input);
+                std::process::exit(8);
+            })
+    };
+    let read = |p: &path::Path| std::fs::read_to_string(p).unwrap_or_else(|_| panic!("Could not find file {:?}", p));
+    let core = read(input);
+    let time = read(&find(&["tim", "time", "TIM"]));
+    let stoch = read(&find(&["sto", "stoch", "STO"]));
+
+    match smps::import(&core, &time, &stoch, options) {
+        Ok(import) => {
+            println!("\n    Imported SMPS variables:\n");
+            for (id, name) in import.names.iter() {
+                println!("{:>8} {}", id, name);
+            }
+            (import.graph, [core, time, stoch].concat())
+        }
+        Err(e) => {
+            eprintln!("Could not import SMPS: {}", e);
+            std::process::exit(8);
+        }
+    }
+}
+
+// The scenario tree the exporters and the solver work over: every outcome, or a
+// sample of them with `--saa`.
+fn scenario_tree(g: &common::ScpGraph, options: &Options) -> common::scenario::ScenarioTree {
+    let tree = match &options.saa {
+        Some(branching) => common::saa::sample(g, branching, options.saa_sampling, &mut options.seed.rng()),
+        None => common::scenario::build(g),
+    };
+    match tree {
+        Ok(tree) => {
+            println!("Scenario tree has {} scenarios", tree.leaves().count());
+            tree
+        }
+        Err(e) => {
+            eprintln!("Could not build the scenario tree: {}", e);
+            std::process::exit(9);
+        }
+    }
+}
+
+// Prints the solution, or why there isn't one; false if the model wasn't solved.
+fn report_solution(solution: Result<common::solve::Solution, common::solve::SolveError>) -> bool {
+    match solution {
+        Ok(solution) => {
+            print_solution(&solution);
+            true
+        }
+        Err(e) => {
+            eprintln!("Could not solve: {}", e);
+            false
+        }
+    }
+}
+
+fn print_solution(solution: &common::solve::Solution) {
+    println!("Expected objective: {}", solution.objective);
+    for (name, value) in solution.first_stage.iter() {
+        println!("{:>8} = {:?}", name, value);
+    }
+}
+
+fn print_gap(gap: &common::saa::GapEstimate, branching: &[usize], evaluation: &[usize]) {
+    let z = gap.z();
+    println!("\n    SAA gap estimate ({} replications at {:?}, evaluated at {:?}):\n", gap.replications, branching, evaluation);
+    println!("Lower bound {} ± {}", gap.lower, z * gap.lower_variance.sqrt());
+    println!("Upper bound {} ± {}", gap.upper, z * gap.upper_variance.sqrt());
+    println!("Gap         {}, at most {} with {}% confidence", gap.gap(), gap.gap_bound(), gap.confidence * 100.);
+    println!("Candidate first stage:");
+    for (name, value) in gap.candidate.iter() {
+        println!("{:>8} = {:?}", name, value);
+    }
+}
+
+pub fn main() {
+    use std::io::Write;
+
+    let args: Vec<String> = std::env::args().collect();
+
+    let options = match parse_options(&args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: compiler <input> <output> [--json] [--dot <path>] [--dot-stages] [--decompile <path>] [--minizinc <path>] [--lp <path>] [--mps <path>] [--stats <path>] [--discretize <method:n>] [--discretize-var <var>=<method:n>]... [--smps [--smps-grid <n>] [--smps-bound <b>]] [--saa <b1,b2,…> [--saa-lhs] [--saa-gap <m>]] [--solve]");
+            std::process::exit(1);
+        }
+    };
+
+    let fpath = options.input.clone();
+    let opath = options.output.clone();
+
+    let (mut g, program) = if options.smps {
+        import_smps(&fpath, &options.smps_options)
+    } else {
+        let program = std::fs::read_to_string(&fpath).expect(&format!("Could not find file {:?}", &fpath));
+        (compile_source(&program), program)
+    };
+    if options.discretize.is_some() || !options.discretize_vars.is_empty() {
+        match common::discretize::discretize_graph(&mut g, options.discretize, &options.discretize_vars) {
+            Ok(changed) => {
+                let changed: Vec<&str> = changed.iter().map(|n| n.as_str()).collect();
+                println!("Discretized {}", if changed.is_empty() { String::from("nothing") } else { changed.join(", ") });
+            }
+            Err(e) => {
+                eprintln!("Could not discretize: {}", e);
+                std::process::exit(11);
+            }
+        }
+    }
+    g.compact();
+
+    println!("\n==============\n    Graph:\n==============\n");
+    graph::pretty_print(&g);
+
+    let stats = common::stats::statistics(&g);
+    println!("\n    Statistics:\n");
+    print!("{}", stats);
+    if let Some(p) = &options.stats {
+        println!("Writing statistics to {:?}…", p);
+        std::fs::write(p, common::stats::to_json(&stats)).unwrap();
+    }
+
+    if let Some(dpath) = &options.dot {
+        println!("Writing DOT graph to {:?}…", dpath);
+        std::fs::write(dpath, dot::to_dot(&g, options.dot_stages)).unwrap();
+    }
+
+    if let Some(spath) = &options.decompile {
+        println!("Writing decompiled program to {:?}…", spath);
+        std::fs::write(spath, decompile::decompile(&g)).unwrap();
+    }
+
+    if options.saa.is_some() {
+        // rerun with `--seed` to draw the same scenarios
+        println!("Sampling with seed {}", options.seed);
+    }
+
+    // the graph is still saved when solving fails, and the exit code reports it after
+    let mut solved = true;
+    let exports = options.minizinc.is_some() || options.lp.is_some() || options.mps.is_some();
+    if exports || (options.solve && options.saa.is_some()) {
+        let tree = scenario_tree(&g, &options);
+        if let Some(p) = &options.minizinc {
+            write_export(p, "MiniZinc", minizinc::to_minizinc(&g, &tree), 6);
+        }
+        if let Some(p) = &options.lp {
+            write_export(p, "LP", lp::to_lp(&g, &tree), 7);
+        }
+        if let Some(p) = &options.mps {
+            write_export(p, "MPS", lp::to_mps(&g, &tree), 7);
+        }
+        if options.solve && options.saa.is_some() {
+            solved = report_solution(common::solve::solve(&g, &tree));
+        }
+    }
+    if options.solve && options.saa.is_none() {
+        // enumerates the outcomes itself, so distributions may read decisions
+        solved = report_solution(common::solve::solve_exact(&g));
+    }
+
+    if let (Some(replications), Some(branching)) = (options.saa_gap, &options.saa) {
+        // the candidate is judged on ten times as many samples per node
+        let evaluation: Vec<usize> = branching.iter().map(|b| b * 10).collect();
+        let estimate = common::saa::estimate_gap(
+            &g, branching, &evaluation, options.saa_sampling, replications, 0.95, &mut options.seed.child(1).rng(),
+        );
+        match estimate {
+            Ok(gap) => print_gap(&gap, branching, &evaluation),
+            Err(e) => {
+                eprintln!("Could not estimate the SAA gap: {}", e);
+                std::process::exit(10);
+            }
+        }
+    }
+
     println!("Saving to {:?}…", &opath);
-    let serialized = bincode::serialize(&g).unwrap();
+    let serialized = if options.json {
+        common::json::to_json(&g).into_bytes()
+    } else {
+        common::container::write(&g, env!("CARGO_PKG_VERSION"), &program)
+    };
     let mut f = std::fs::File::create(opath).unwrap();
     f.write_all(&serialized).unwrap();
     println!("Done.");
+    if !solved {
+        std::process::exit(10);
+    }
 }
@@ -0,0 +1,359 @@
+use crate::partial_eval::EE;
+use common::scenario::{self, ScenarioTree};
+use common::*;
+use primitives::{Domain, Primitive};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+
+#[derive(Debug)]
+pub enum MznError {
+    Unsupported(String),
+}
+
+impl std::fmt::Display for MznError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Ty {
+    Int,
+    Float,
+    Bool,
+}
+
+// a MiniZinc expression and its type
+struct Mzn {
+    s: String,
+    ty: Ty,
+}
+
+impl Mzn {
+    fn new(s: String, ty: Ty) -> Self {
+        Mzn{s, ty}
+    }
+
+    fn float(self) -> Result<String, MznError> {
+        match self.ty {
+            Ty::Float => Ok(self.s),
+            Ty::Int => Ok(format!("int2float({})", self.s)),
+            Ty::Bool => Err(MznError::Unsupported(format!("{} is used as a number", self.s))),
+        }
+    }
+
+    fn boolean(self) -> Result<String, MznError> {
+        match self.ty {
+            Ty::Bool => Ok(self.s),
+            Ty::Int => Ok(format!("({} != 0)", self.s)),
+            Ty::Float => Ok(format!("({} != 0.0)", self.s)),
+        }
+    }
+}
+
+// brings two numeric expressions to a common type
+fn unify(l: Mzn, r: Mzn) -> Result<(String, String, Ty), MznError> {
+    match (l.ty, r.ty) {
+        (Ty::Int, Ty::Int) => Ok((l.s, r.s, Ty::Int)),
+        (Ty::Bool, Ty::Bool) => Ok((l.s, r.s, Ty::Bool)),
+        _ => Ok((l.float()?, r.float()?, Ty::Float)),
+    }
+}
+
+fn ident(name: &str) -> String {
+    let mut s: String = name.trim_start_matches('@').chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        s.insert(0, 'v');
+    }
+    s
+}
+
+fn literal(p: &Primitive) -> Result<Mzn, MznError> {
+    match p {
+        Primitive::Boolean(b) => Ok(Mzn::new(format!("{}", b), Ty::Bool)),
+        Primitive::Int(i) if *i < 0 => Ok(Mzn::new(format!("({})", i), Ty::Int)),
+        Primitive::Int(i) => Ok(Mzn::new(format!("{}", i), Ty::Int)),
+        Primitive::Float(f) if !f.is_finite() => Err(MznError::Unsupported(format!("{} has no MiniZinc form", f))),
+        Primitive::Float(f) if *f < 0. => Ok(Mzn::new(format!("({:?})", f), Ty::Float)),
+        Primitive::Float(f) => Ok(Mzn::new(format!("{:?}", f), Ty::Float)),
+        other => Err(MznError::Unsupported(format!("{:?} has no MiniZinc form", other))),
+    }
+}
+
+// what every variable reads as in one scenario
+struct Scope<'a> {
+    graph: &'a ScpGraph,
+    tree: &'a ScenarioTree,
+    leaf: usize,
+    values: HashMap<Identifier, Primitive>,
+    depths: &'a [usize],
+    types: &'a [Ty],
+}
+
+impl<'a> Scope<'a> {
+    fn var(&self, id: &Identifier) -> Result<Mzn, MznError> {
+        if let Some(v) = self.values.get(id) {
+            return literal(v);
+        }
+        match self.graph.variables.get_by_name(id) {
+            Some(r) if r.kind == VariableKind::Decision => {
+                let node = self.tree.ancestor(self.leaf, self.depths[r.id as usize]);
+                Ok(Mzn::new(copy_name(id, node), self.types[r.id as usize]))
+            }
+            _ => Err(MznError::Unsupported(format!("{} is not a variable of the graph", id))),
+        }
+    }
+}
+
+fn copy_name(id: &Identifier, node: usize) -> String {
+    format!("{}_{}", ident(id), node)
+}
+
+fn expr(tree: &EvaluatedTree, at: ExpressionRef, scope: &Scope) -> Result<Mzn, MznError> {
+    let arg = |i: usize, args: &[ExpressionRef]| expr(tree, args[i], scope);
+    match tree.deref(at) {
+        EE::C(c) => literal(c),
+        EE::VarRef(id) => scope.var(id),
+        EE::Decision{id, body: _} | EE::Stochastic{id, body: _} => scope.var(id),
+        EE::Begin(v) => match v.last() {
+            Some(last) => expr(tree, *last, scope),
+            None => Err(MznError::Unsupported(String::from("empty begin"))),
+        },
+        EE::If{predicate, consequent, alternative} => {
+            let p = expr(tree, *predicate, scope)?.boolean()?;
+            let (c, a, ty) = unify(expr(tree, *consequent, scope)?, expr(tree, *alternative, scope)?)?;
+            Ok(Mzn::new(format!("(if {} then {} else {} endif)", p, c, a), ty))
+        }
+        EE::Builtin{builtin, args} => {
+            let arity = match builtin {
+                Builtin::Sqrt | Builtin::Abs | Builtin::Ln => 1,
+                Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div | Builtin::Pow
+                | Builtin::IsLess | Builtin::IsEqual | Builtin::IsGreater | Builtin::And | Builtin::Or => 2,
+                // anything else is only written out if it is fixed once the scenario is
+                _ => return match scenario::eval(tree, at, &scope.values) {
+                    Ok(value) => literal(&value),
+                    Err(_) => Err(MznError::Unsupported(format!("({} ...) has no MiniZinc form", builtin.name()))),
+                },
+            };
+            if args.len() != arity {
+                return Err(MznError::Unsupported(format!("({} ...) takes {} arguments", builtin.name(), arity)));
+            }
+            match builtin {
+                Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Pow => {
+                    let (l, r, ty) = unify(arg(0, args)?, arg(1, args)?)?;
+                    if ty == Ty::Bool {
+                        return Err(MznError::Unsupported(format!("({} ...) of booleans", builtin.name())));
+                    }
+                    Ok(Mzn::new(match builtin {
+                        Builtin::Add => format!("({} + {})", l, r),
+                        Builtin::Sub => format!("({} - {})", l, r),
+                        Builtin::Mul => format!("({} * {})", l, r),
+                        _ => format!("pow({}, {})", l, r),
+                    }, ty))
+                }
+                // FOPPL division is always floating point
+                Builtin::Div => Ok(Mzn::new(format!("({} / {})", arg(0, args)?.float()?, arg(1, args)?.float()?), Ty::Float)),
+                Builtin::Sqrt => Ok(Mzn::new(format!("sqrt({})", arg(0, args)?.float()?), Ty::Float)),
+                Builtin::Ln => Ok(Mzn::new(format!("ln({})", arg(0, args)?.float()?), Ty::Float)),
+                Builtin::Abs => {
+                    let x = arg(0, args)?;
+                    Ok(Mzn::new(format!("abs({})", x.s), x.ty))
+                }
+                Builtin::IsLess | Builtin::IsEqual | Builtin::IsGreater => {
+                    let (l, r, _) = unify(arg(0, args)?, arg(1, args)?)?;
+                    let op = match builtin {
+                        Builtin::IsLess => "<",
+                        Builtin::IsEqual => "=",
+                        _ => ">",
+                    };
+                    Ok(Mzn::new(format!("({} {} {})", l, op, r), Ty::Bool))
+                }
+                _ => {
+                    let op = if *builtin == Builtin::And { "/\\" } else { "\\/" };
+                    Ok(Mzn::new(format!("({} {} {})", arg(0, args)?.boolean()?, op, arg(1, args)?.boolean()?), Ty::Bool))
+                }
+            }
+        }
+        EE::Distribution{distribution, args: _} => {
+            Err(MznError::Unsupported(format!("({} ...) has no MiniZinc form", distribution.name())))
+        }
+        EE::Constrain{prob: _, relation: _, left: _, right: _} => {
+            Err(MznError::Unsupported(String::from("a constraint is used as a value")))
+        }
+        EE::Placeholder | EE::Deleted => unreachable!(),
+    }
+}
+
+fn relation(r: Relation) -> &'static str {
+    match r {
+        Relation::Eq => "=",
+        Relation::Neq => "!=",
+        Relation::Lt => "<",
+        Relation::Gt => ">",
+        Relation::Leq => "<=",
+        Relation::Geq => ">=",
+    }
+}
+
+// the constraint, implied by its predicates, as it reads in one scenario
+fn constraint(c: &Constraint, scope: &Scope) -> Result<String, MznError> {
+    let (l, r, _) = unify(expr(&c.left, c.left.root(), scope)?, expr(&c.right, c.right.root(), scope)?)?;
+    let core = format!("{} {} {}", l, relation(c.relation), r);
+    if c.predicate.is_empty() {
+        return Ok(core);
+    }
+    let mut guards = Vec::with_capacity(c.predicate.len());
+    for pred in c.predicate.iter() {
+        let p = expr(&pred.pred, pred.pred.root(), scope)?.boolean()?;
+        guards.push(if pred.negated { format!("not {}", p) } else { p });
+    }
+    Ok(format!("({}) -> ({})", guards.join(" /\\ "), core))
+}
+
+fn domain(d: &Primitive) -> Result<(String, Ty), MznError> {
+    match d {
+        Primitive::Domain(Domain::IntRange(a, b)) if b > a => Ok((format!("{}..{}", a, b - 1), Ty::Int)),
+        Primitive::Domain(Domain::OneOf(values)) => {
+            let ty = if values.iter().all(|v| matches!(v, Primitive::Boolean(_))) {
+                return Ok((String::from("bool"), Ty::Bool));
+            } else if values.iter().all(|v| matches!(v, Primitive::Int(_))) {
+                Ty::Int
+            } else {
+                Ty::Float
+            };
+            let mut members = Vec::with_capacity(values.len());
+            for v in values {
+                let m = literal(v)?;
+                members.push(if ty == Ty::Float { m.float()? } else { m.s });
+            }
+            Ok((format!("{{{}}}", members.join(", ")), ty))
+        }
+        other => Err(MznError::Unsupported(format!("decision domain {:?} has no MiniZinc form", other))),
+    }
+}
+
+fn float(f: f64) -> String {
+    format!("{:?}", f)
+}
+
+/// Writes the deterministic equivalent of a graph as a MiniZinc model. Every
+/// decision gets one copy per outcome of the stochastic variables revealed before
+/// its stage, every constraint is stated once per scenario, and a chance
+/// constraint becomes a probability-weighted sum of per-scenario indicators. A
+/// numeric body is minimised in expectation. The scenarios are the leaves of
+/// `tree`, whether it enumerates every outcome or was sampled.
+pub fn to_minizinc(graph: &ScpGraph, tree: &ScenarioTree) -> Result<String, MznError> {
+    let leaves: Vec<usize> = tree.leaves().collect();
+
+    let n = graph.variables.variables.len();
+    let depths = scenario::decision_depths(graph);
+
+    let mut out = String::new();
+    writeln!(out, "% deterministic equivalent over {} scenarios", leaves.len()).unwrap();
+    for (k, leaf) in leaves.iter().enumerate() {
+        let values = tree.assignment(*leaf, &graph.variables);
+        let mut fixed: Vec<String> = tree.levels.iter()
+            .map(|r| {
+                let name = graph.variables.name(*r);
+                match literal(&values[name]) {
+                    Ok(v) => format!("{} = {}", ident(name), v.s),
+                    Err(_) => format!("{} = {:?}", ident(name), values[name]),
+                }
+            })
+            .collect();
+        fixed.sort();
+        writeln!(out, "% scenario {} (p = {}): {}", k, float(tree.nodes[*leaf].probability), fixed.join(", ")).unwrap();
+    }
+    out.push('\n');
+
+    let mut types = vec![Ty::Int; n];
+    for var in graph.variables.iter().filter(|r| r.kind == VariableKind::Decision) {
+        let name = graph.variables.name(var);
+        let definition = &graph.variables.deref(var).definition;
+        let depth = depths[var.id as usize];
+        for node in (0..tree.nodes.len()).filter(|node| tree.nodes[*node].depth == depth) {
+            let values = tree.assignment(node, &graph.variables);
+            let d = scenario::eval(definition, definition.root(), &values)
+                .map_err(|e| MznError::Unsupported(format!("could not evaluate the domain of {}: {}", name, e)))?;
+            let (dom, ty) = domain(&d)?;
+            types[var.id as usize] = ty;
+            writeln!(out, "var {}: {};", dom, copy_name(name, node)).unwrap();
+        }
+    }
+    out.push('\n');
+
+    let scopes: Vec<Scope> = leaves.iter()
+        .map(|leaf| Scope{
+            graph,
+            tree,
+            leaf: *leaf,
+            values: tree.assignment(*leaf, &graph.variables),
+            depths: &depths,
+            types: &types,
+        })
+        .collect();
+
+    let mut hard: Vec<String> = Vec::new();
+    for (i, c) in graph.constraints.iter().enumerate() {
+        if c.probability >= 1. {
+            for scope in scopes.iter() {
+                let s = constraint(c, scope)?;
+                if !hard.contains(&s) {
+                    hard.push(s);
+                }
+            }
+            continue;
+        }
+
+        let mut weighted = Vec::with_capacity(scopes.len());
+        for (k, scope) in scopes.iter().enumerate() {
+            let indicator = format!("c{}_s{}", i, k);
+            writeln!(out, "var bool: {};", indicator).unwrap();
+            writeln!(out, "constraint {} -> ({});", indicator, constraint(c, scope)?).unwrap();
+            weighted.push(format!("{} * bool2float({})", float(tree.nodes[scope.leaf].probability), indicator));
+        }
+        writeln!(out, "constraint {} >= {};", weighted.join(" + "), float(c.probability)).unwrap();
+    }
+    for s in hard {
+        writeln!(out, "constraint {};", s).unwrap();
+    }
+    out.push('\n');
+
+    // the body is minimised when it is a number in every scenario
+    let mut objective = Err(String::from("the program ends on a constraint"));
+    if !graph.body.is_nil() {
+        let mut terms = Vec::with_capacity(scopes.len());
+        for scope in scopes.iter() {
+            let term = expr(&graph.body, graph.body.root(), scope)
+                .and_then(|body| body.float())
+                .map(|body| format!("{} * {}", float(tree.nodes[scope.leaf].probability), body));
+            match term {
+                Ok(t) => terms.push(t),
+                Err(e) => {
+                    objective = Err(e.to_string());
+                    break;
+                }
+            }
+        }
+        if terms.len() == scopes.len() {
+            objective = Ok(terms.join(" + "));
+        }
+    }
+    match objective {
+        Ok(o) => {
+            writeln!(out, "var float: objective = {};", o).unwrap();
+            writeln!(out, "solve minimize objective;").unwrap();
+        }
+        Err(e) => {
+            writeln!(out, "% nothing to minimise: {}", e).unwrap();
+            writeln!(out, "solve satisfy;").unwrap();
+        }
+    }
+
+    Ok(out)
+}
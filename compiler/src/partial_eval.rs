@@ -3,8 +3,7 @@
 pub use crate::desugar::*;
 use common::{*, primitives::*, distribution::build_distribution};
 
-mod eval;
-use eval::*;
+use common::eval::*;
 
 
 
@@ -30,6 +29,11 @@ fn fresh(state: &mut u32, varkind: VariableKind) -> Identifier {
     Identifier::from(s)
 }
 
+/// The position at which a variable named by `fresh` was bound in the program.
+pub fn binding_order(id: &Identifier) -> Option<u32> {
+    id.get(2..).and_then(|n| n.parse().ok())
+}
+
 fn append_fresh(state: &mut u32, base: &Identifier) -> Identifier {
     use std::fmt::Write;
 
@@ -44,7 +48,7 @@ pub fn partial_eval(src: &ExpressionTree<Identifier>) -> Result<EvaluatedTree, P
     let mut name_state = 0;
     let mut out = EvaluatedTree::new();
     _partial_eval(src, src.root(), &mut out, &im::HashMap::new(), &mut name_state, None)?;
-    Ok(out)
+    Ok(out.compact())
 }
 
 pub enum PartialEvalErr {
@@ -0,0 +1,686 @@
+use crate::partial_eval::EE;
+use common::*;
+use common::primitives::*;
+use std::collections::BTreeMap;
+
+
+// products that would expand into more terms than this are kept factored
+const MAX_EXPANDED_TERMS: usize = 64;
+// `(pow e n)` is only expanded for constant 0 <= n <= this
+const MAX_EXPANDED_POWER: i128 = 4;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn from_primitive(p: &Primitive) -> Option<Number> {
+        match p {
+            Primitive::Int(i) => Some(Number::Int(*i)),
+            Primitive::Float(f) => Some(Number::Float(*f)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_primitive(self) -> Primitive {
+        match self {
+            Number::Int(i) => Primitive::from(i),
+            Number::Float(f) => Primitive::from(f),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    fn add(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Int(l), Number::Int(r)) => match l.checked_add(r) {
+                Some(i) => Number::Int(i),
+                None => Number::Float(l as f64 + r as f64),
+            },
+            _ => Number::Float(self.as_f64() + other.as_f64()),
+        }
+    }
+
+    fn mul(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Int(l), Number::Int(r)) => match l.checked_mul(r) {
+                Some(i) => Number::Int(i),
+                None => Number::Float(l as f64 * r as f64),
+            },
+            _ => Number::Float(self.as_f64() * other.as_f64()),
+        }
+    }
+
+    fn neg(self) -> Number {
+        self.mul(Number::Int(-1))
+    }
+
+    fn abs(self) -> Number {
+        if self.is_negative() { self.neg() } else { self }
+    }
+
+    fn is_zero(self) -> bool {
+        self.as_f64() == 0.
+    }
+
+    fn is_one(self) -> bool {
+        self.as_f64() == 1.
+    }
+
+    fn is_negative(self) -> bool {
+        self.as_f64() < 0.
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub(crate) enum Atom {
+    Var(Identifier),
+    // a simplified subtree that isn't itself a polynomial, e.g. `(sqrt x)`
+    Opaque(Box<EvaluatedTree>),
+}
+
+type MonomialKey = Vec<(String, u32)>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Term {
+    // sorted by key, as in the `MonomialKey`
+    pub(crate) factors: Vec<(String, Atom, u32)>,
+    pub(crate) coefficient: Number,
+}
+
+impl Term {
+    fn key(&self) -> MonomialKey {
+        self.factors.iter().map(|(k, _, p)| (k.clone(), *p)).collect()
+    }
+
+    fn mul(&self, other: &Term) -> Term {
+        let mut factors = self.factors.clone();
+        for (key, atom, power) in other.factors.iter() {
+            match factors.binary_search_by(|(k, _, _)| k.cmp(key)) {
+                Ok(i) => factors[i].2 += power,
+                Err(i) => factors.insert(i, (key.clone(), atom.clone(), *power)),
+            }
+        }
+        Term{factors, coefficient: self.coefficient.mul(other.coefficient)}
+    }
+}
+
+/// A sum of products of atoms, kept in a canonical order: monomials are ordered by
+/// the names of their factors, with the constant term (the empty monomial) first.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Poly {
+    pub(crate) terms: BTreeMap<MonomialKey, Term>,
+}
+
+impl Poly {
+    fn constant(n: Number) -> Poly {
+        let mut p = Poly::default();
+        p.push(Term{factors: Vec::new(), coefficient: n});
+        p
+    }
+
+    fn atom(key: String, atom: Atom) -> Poly {
+        let mut p = Poly::default();
+        p.push(Term{factors: vec![(key, atom, 1)], coefficient: Number::Int(1)});
+        p
+    }
+
+    pub(crate) fn push(&mut self, term: Term) {
+        let key = term.key();
+        let merged = match self.terms.get(&key) {
+            Some(existing) => existing.coefficient.add(term.coefficient),
+            None => term.coefficient,
+        };
+        if merged.is_zero() {
+            self.terms.remove(&key);
+        } else {
+            self.terms.insert(key, Term{factors: term.factors, coefficient: merged});
+        }
+    }
+
+    fn add(mut self, other: Poly) -> Poly {
+        for (_, term) in other.terms {
+            self.push(term);
+        }
+        self
+    }
+
+    pub(crate) fn scale(self, by: Number) -> Poly {
+        let mut out = Poly::default();
+        for (_, mut term) in self.terms {
+            term.coefficient = term.coefficient.mul(by);
+            out.push(term);
+        }
+        out
+    }
+
+    pub(crate) fn sub(self, other: Poly) -> Poly {
+        self.add(other.scale(Number::Int(-1)))
+    }
+
+    fn mul(&self, other: &Poly) -> Poly {
+        let mut out = Poly::default();
+        for l in self.terms.values() {
+            for r in other.terms.values() {
+                out.push(l.mul(r));
+            }
+        }
+        out
+    }
+
+    fn as_constant(&self) -> Option<Number> {
+        match self.terms.len() {
+            0 => Some(Number::Int(0)),
+            1 => self.terms.get(&Vec::new()).map(|t| t.coefficient),
+            _ => None,
+        }
+    }
+
+    fn take_constant(&mut self) -> Number {
+        self.terms.remove(&Vec::new()).map(|t| t.coefficient).unwrap_or(Number::Int(0))
+    }
+
+    fn leading_coefficient(&self) -> Option<Number> {
+        self.terms.iter().find(|(k, _)| !k.is_empty()).map(|(_, t)| t.coefficient)
+    }
+}
+
+
+/// Simplifies a tree: integer arithmetic is rewritten into a canonical
+/// sum-of-products over the variables it references (dropping `x*0`, `x+0` and
+/// `x*1` and folding constants along the way), integer comparisons are put into
+/// `vars <? constant` form and constant `if`s, `and`s and `or`s are folded.
+/// Reassociating float arithmetic changes how it rounds, so anything that may be
+/// a float keeps its shape, with only its integer subtrees simplified.
+pub fn simplify(tree: &EvaluatedTree, variables: &Variables) -> EvaluatedTree {
+    let mut scratch = EvaluatedTree::new();
+    let root = simplify_at(tree, tree.root(), variables, &mut scratch);
+    let mut out = EvaluatedTree::new();
+    scratch.copy_into(&mut out, root);
+    out
+}
+
+/// Simplifies both sides of a constraint and its predicates. When both sides are
+/// integer-valued, every variable term is moved to the left and the constant to
+/// the right, with the leading coefficient made positive. Float rounding depends
+/// on the order of the arithmetic, so a constraint over floats keeps its shape.
+pub fn simplify_constraint(constraint: &mut Constraint, variables: &Variables) {
+    for pred in constraint.predicate.iter_mut() {
+        pred.pred = simplify(&pred.pred, variables);
+    }
+
+    let numeric = is_numeric_side(&constraint.left, constraint.left.root(), variables)
+        && is_numeric_side(&constraint.right, constraint.right.root(), variables);
+    if numeric {
+        let left = poly(&constraint.left, constraint.left.root(), variables);
+        let right = poly(&constraint.right, constraint.right.root(), variables);
+        let difference = left.sub(right);
+        if is_integral(&difference, variables) && difference.as_constant().is_none() {
+            let (relation, left, right) = normalize(difference, constraint.relation);
+            constraint.relation = relation;
+            constraint.left = emit_root(&left);
+            constraint.right = emit_root(&Poly::constant(right));
+            return;
+        }
+    }
+
+    constraint.left = simplify(&constraint.left, variables);
+    constraint.right = simplify(&constraint.right, variables);
+}
+
+fn normalize(mut difference: Poly, relation: Relation) -> (Relation, Poly, Number) {
+    let constant = difference.take_constant().neg();
+    match difference.leading_coefficient() {
+        Some(c) if c.is_negative() => {
+            (relation.mirrored(), difference.scale(Number::Int(-1)), constant.neg())
+        }
+        _ => (relation, difference, constant),
+    }
+}
+
+// whether the polynomial only ever takes integer values, so that rearranging it
+// is exact
+fn is_integral(p: &Poly, variables: &Variables) -> bool {
+    p.terms.values().all(|t| matches!(t.coefficient, Number::Int(_))
+        && t.factors.iter().all(|(_, atom, _)| match atom {
+            Atom::Var(id) => variables.get_by_name(id).is_some_and(|r| is_integral_variable(variables.deref(r))),
+            Atom::Opaque(_) => false,
+        }))
+}
+
+fn is_integral_variable(var: &Variable) -> bool {
+    let is_int = |p: &Primitive| matches!(p, Primitive::Int(_));
+    match var.definition.deref(var.definition.root()) {
+        EE::C(Primitive::Domain(Domain::IntRange(_, _))) => true,
+        EE::C(Primitive::Domain(Domain::OneOf(v))) => v.iter().all(is_int),
+        EE::C(Primitive::Distribution(d)) => is_integral_distribution(d),
+        EE::Distribution{distribution, args: _} => matches!(distribution,
+            DistributionType::Kronecker | DistributionType::UniformDiscrete
+            | DistributionType::Categorical | DistributionType::Binomial
+            | DistributionType::Poisson | DistributionType::Geometric
+            | DistributionType::NegativeBinomial),
+        EE::Builtin{builtin: Builtin::IntRange, args: _} => true,
+        _ => false,
+    }
+}
+
+fn is_integral_distribution(d: &Distribution) -> bool {
+    match d {
+        Distribution::Kronecker{center: _} | Distribution::UniformDiscrete{a: _, b: _}
+        | Distribution::Categorical{weights: _} | Distribution::Binomial{n: _, p: _}
+        | Distribution::Poisson{lambda: _} | Distribution::Geometric{p: _}
+        | Distribution::NegativeBinomial{r: _, p: _} => true,
+        Distribution::MappedCategorical{weights: _, values}
+        | Distribution::UniformChoice{values} => values.iter().all(|p| matches!(p, Primitive::Int(_))),
+        Distribution::Mixture{weights: _, components} => components.iter().all(is_integral_distribution),
+        Distribution::Truncated{distribution, lo: _, hi: _, mass: _} => is_integral_distribution(distribution),
+        _ => false,
+    }
+}
+
+fn is_numeric_side(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables) -> bool {
+    match tree.deref(at) {
+        EE::C(c) => Number::from_primitive(c).is_some(),
+        EE::Begin(v) if v.len() == 1 => is_numeric_side(tree, v[0], variables),
+        EE::VarRef(id) => match variables.get_by_name(id) {
+            Some(r) => is_numeric_variable(variables.deref(r)),
+            None => false,
+        },
+        EE::Builtin{builtin, args: _} => matches!(builtin,
+            Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div | Builtin::Pow
+            | Builtin::Sqrt | Builtin::Abs | Builtin::Ln),
+        _ => false,
+    }
+}
+
+// whether every value a variable can take is an int or a float
+pub(crate) fn is_numeric_variable(var: &Variable) -> bool {
+    let is_num = |p: &Primitive| Number::from_primitive(p).is_some();
+    match var.definition.deref(var.definition.root()) {
+        EE::C(Primitive::Domain(Domain::IntRange(_, _))) => true,
+        EE::C(Primitive::Domain(Domain::OneOf(v))) => v.iter().all(is_num),
+        EE::C(Primitive::Distribution(d)) => is_numeric_distribution(d),
+        // the components aren't known until the arguments are
+        EE::Distribution{distribution, args: _} => !matches!(distribution,
+            DistributionType::MappedCategorical | DistributionType::UniformChoice
+            | DistributionType::Dirichlet | DistributionType::Bernoulli
+            | DistributionType::Mixture | DistributionType::Truncate
+            | DistributionType::Empirical),
+        EE::Builtin{builtin: Builtin::IntRange, args: _} => true,
+        _ => false,
+    }
+}
+
+fn is_numeric_distribution(d: &Distribution) -> bool {
+    match d {
+        Distribution::MappedCategorical{weights: _, values}
+        | Distribution::UniformChoice{values} => values.iter().all(|p| Number::from_primitive(p).is_some()),
+        Distribution::Dirichlet{weights: _} | Distribution::Bernoulli{p: _} => false,
+        Distribution::Mixture{weights: _, components} => components.iter().all(is_numeric_distribution),
+        Distribution::Truncated{distribution, lo: _, hi: _, mass: _} => is_numeric_distribution(distribution),
+        _ => true,
+    }
+}
+
+fn is_arithmetic(tree: &EvaluatedTree, at: ExpressionRef) -> bool {
+    match tree.deref(at) {
+        EE::C(c) => Number::from_primitive(c).is_some(),
+        EE::VarRef(_) => true,
+        EE::Begin(v) if v.len() == 1 => is_arithmetic(tree, v[0]),
+        EE::Builtin{builtin, args} => args.len() == 2 && matches!(builtin,
+            Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Div | Builtin::Pow),
+        _ => false,
+    }
+}
+
+pub(crate) fn poly(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables) -> Poly {
+    let poly = |at: ExpressionRef| poly(tree, at, variables);
+    let opaque = |at: ExpressionRef| opaque(tree, at, variables);
+    match tree.deref(at) {
+        EE::C(c) => match Number::from_primitive(c) {
+            Some(n) => Poly::constant(n),
+            None => opaque(at),
+        },
+        EE::VarRef(id) => Poly::atom(id.to_string(), Atom::Var(id.clone())),
+        EE::Begin(v) if v.len() == 1 => poly(v[0]),
+        EE::Builtin{builtin, args} if args.len() == 2 => match builtin {
+            Builtin::Add => poly(args[0]).add(poly(args[1])),
+            Builtin::Sub => poly(args[0]).sub(poly(args[1])),
+            Builtin::Mul => product(poly(args[0]), poly(args[1])),
+            // `/` is float division. Folding it is only safe when that gives the
+            // same bits as evaluating it: either both sides are constant, or the
+            // divisor's reciprocal is exact, so that scaling by it rounds the same.
+            Builtin::Div => {
+                let dividend = poly(args[0]);
+                match (dividend.as_constant(), poly(args[1]).as_constant()) {
+                    (Some(a), Some(c)) if !c.is_zero() => Poly::constant(Number::Float(a.as_f64() / c.as_f64())),
+                    (_, Some(c)) if has_exact_reciprocal(c) => dividend.scale(Number::Float(1. / c.as_f64())),
+                    _ => opaque(at),
+                }
+            }
+            Builtin::Pow => match poly(args[1]).as_constant() {
+                Some(Number::Int(n)) if (0..=MAX_EXPANDED_POWER).contains(&n) => {
+                    let base = poly(args[0]);
+                    let mut out = Poly::constant(Number::Int(1));
+                    for _ in 0..n {
+                        out = product(out, base.clone());
+                    }
+                    out
+                }
+                _ => opaque(at),
+            },
+            _ => opaque(at),
+        },
+        _ => opaque(at),
+    }
+}
+
+// whether 1/c is exact, i.e. c is a power of two whose reciprocal is also normal
+fn has_exact_reciprocal(c: Number) -> bool {
+    let c = c.as_f64();
+    const MANTISSA: u64 = (1 << 52) - 1;
+    c.is_normal() && (1. / c).is_normal() && c.to_bits() & MANTISSA == 0
+}
+
+fn product(l: Poly, r: Poly) -> Poly {
+    if l.terms.len() * r.terms.len() <= MAX_EXPANDED_TERMS {
+        l.mul(&r)
+    } else {
+        as_atom(emit_root(&l)).mul(&as_atom(emit_root(&r)))
+    }
+}
+
+// simplifies a non-polynomial subtree and wraps it up as a single atom
+fn opaque(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables) -> Poly {
+    let mut scratch = EvaluatedTree::new();
+    let root = simplify_structure(tree, at, variables, &mut scratch);
+    let mut out = EvaluatedTree::new();
+    scratch.copy_into(&mut out, root);
+    as_atom(out)
+}
+
+fn as_atom(tree: EvaluatedTree) -> Poly {
+    match tree.deref(tree.root()) {
+        EE::C(c) if Number::from_primitive(c).is_some() => {
+            Poly::constant(Number::from_primitive(c).unwrap())
+        }
+        EE::VarRef(id) => Poly::atom(id.to_string(), Atom::Var(id.clone())),
+        _ => {
+            let mut key = String::from("~");
+            render(&tree, tree.root(), &mut key);
+            Poly::atom(key, Atom::Opaque(Box::new(tree)))
+        }
+    }
+}
+
+fn render(tree: &EvaluatedTree, at: ExpressionRef, out: &mut String) {
+    use std::fmt::Write;
+
+    let list = |head: String, args: &[ExpressionRef], out: &mut String| {
+        write!(out, "({}", head).unwrap();
+        for arg in args {
+            out.push(' ');
+            render(tree, *arg, out);
+        }
+        out.push(')');
+    };
+    match tree.deref(at) {
+        EE::C(c) => write!(out, "{:?}", c).unwrap(),
+        EE::VarRef(id) => out.push_str(id),
+        EE::Begin(v) => list("begin".into(), v, out),
+        EE::Decision{id, body} => list(format!("decision {}", id), &[*body], out),
+        EE::Stochastic{id, body} => list(format!("sample {}", id), &[*body], out),
+        EE::If{predicate, consequent, alternative} => {
+            list("if".into(), &[*predicate, *consequent, *alternative], out)
+        }
+        EE::Constrain{prob, relation, left, right} => {
+            list(format!("constrain {} {}", prob, relation.pretty_print()), &[*left, *right], out)
+        }
+        EE::Builtin{builtin, args} => list(format!("{:?}", builtin), args, out),
+        EE::Distribution{distribution, args} => list(format!("{:?}", distribution), args, out),
+        EE::Placeholder => out.push_str("{placeholder}"),
+        EE::Deleted => out.push_str("{deleted}"),
+    }
+}
+
+fn simplify_at(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables, to: &mut EvaluatedTree) -> ExpressionRef {
+    if is_arithmetic(tree, at) {
+        let p = poly(tree, at, variables);
+        if is_integral(&p, variables) {
+            return emit(&p, to);
+        }
+    }
+    simplify_structure(tree, at, variables, to)
+}
+
+// simplifies the children of a node that isn't itself integer arithmetic
+fn simplify_structure(tree: &EvaluatedTree, at: ExpressionRef, variables: &Variables, to: &mut EvaluatedTree) -> ExpressionRef {
+    let simplify_at = |at: ExpressionRef, to: &mut EvaluatedTree| simplify_at(tree, at, variables, to);
+    let simplify_all = |args: &[ExpressionRef], to: &mut EvaluatedTree| -> Vec<ExpressionRef> {
+        args.iter().map(|arg| simplify_at(*arg, to)).collect()
+    };
+    let copy = match tree.deref(at) {
+        EE::Begin(v) if v.len() == 1 => {
+            return simplify_at(v[0], to);
+        }
+        EE::Begin(v) => EE::Begin(simplify_all(v, to)),
+        EE::If{predicate, consequent, alternative} => {
+            let predicate = simplify_at(*predicate, to);
+            match as_bool(to, predicate) {
+                Some(true) => return simplify_at(*consequent, to),
+                Some(false) => return simplify_at(*alternative, to),
+                None => (),
+            }
+            let consequent = simplify_at(*consequent, to);
+            let alternative = simplify_at(*alternative, to);
+            EE::If{predicate, consequent, alternative}
+        }
+        EE::Decision{id, body} => EE::Decision{id: id.clone(), body: simplify_at(*body, to)},
+        EE::Stochastic{id, body} => EE::Stochastic{id: id.clone(), body: simplify_at(*body, to)},
+        EE::Constrain{prob, relation, left, right} => {
+            let left = simplify_at(*left, to);
+            let right = simplify_at(*right, to);
+            EE::Constrain{prob: *prob, relation: *relation, left, right}
+        }
+        EE::Builtin{builtin, args} if args.len() == 2 => match builtin {
+            // moving terms between the sides of a comparison is only exact when
+            // both are integer-valued
+            Builtin::IsLess | Builtin::IsGreater | Builtin::IsEqual => {
+                let l = poly(tree, args[0], variables);
+                let r = poly(tree, args[1], variables);
+                if is_integral(&l, variables) && is_integral(&r, variables) {
+                    return emit_comparison(*builtin, l, r, to);
+                }
+                EE::Builtin{builtin: *builtin, args: simplify_all(args, to)}
+            }
+            Builtin::And | Builtin::Or => {
+                let l = simplify_at(args[0], to);
+                let r = simplify_at(args[1], to);
+                // (and false x) = false, (and true x) = x, and dually for `or`
+                let absorbing = *builtin == Builtin::Or;
+                return match (as_bool(to, l), as_bool(to, r)) {
+                    (Some(b), _) if b == absorbing => l,
+                    (_, Some(b)) if b == absorbing => r,
+                    (Some(_), _) => r,
+                    (_, Some(_)) => l,
+                    _ => to.push(EE::Builtin{builtin: *builtin, args: vec![l, r]}),
+                };
+            }
+            _ => EE::Builtin{builtin: *builtin, args: simplify_all(args, to)},
+        },
+        EE::Builtin{builtin, args} => EE::Builtin{builtin: *builtin, args: simplify_all(args, to)},
+        EE::Distribution{distribution, args} => {
+            EE::Distribution{distribution: *distribution, args: simplify_all(args, to)}
+        }
+        other => other.clone(),
+    };
+    to.push(copy)
+}
+
+fn as_bool(tree: &EvaluatedTree, at: ExpressionRef) -> Option<bool> {
+    match tree.deref(at) {
+        EE::C(Primitive::Boolean(b)) => Some(*b),
+        _ => None,
+    }
+}
+
+fn emit_comparison(builtin: Builtin, l: Poly, r: Poly, to: &mut EvaluatedTree) -> ExpressionRef {
+    // (>? a b) is rewritten as (<? b a) before moving everything to one side
+    let (relation, difference) = match builtin {
+        Builtin::IsLess => (Relation::Lt, l.sub(r)),
+        Builtin::IsGreater => (Relation::Lt, r.sub(l)),
+        _ => (Relation::Eq, l.sub(r)),
+    };
+    if let Some(c) = difference.as_constant() {
+        let holds = match relation {
+            Relation::Lt => c.as_f64() < 0.,
+            _ => c.is_zero(),
+        };
+        return to.push(EE::C(Primitive::from(holds)));
+    }
+    let (relation, left, right) = normalize(difference, relation);
+    let builtin = match relation {
+        Relation::Lt => Builtin::IsLess,
+        Relation::Gt => Builtin::IsGreater,
+        _ => Builtin::IsEqual,
+    };
+    let left = emit(&left, to);
+    let right = to.push(EE::C(right.to_primitive()));
+    to.push(EE::Builtin{builtin, args: vec![left, right]})
+}
+
+pub(crate) fn emit_root(p: &Poly) -> EvaluatedTree {
+    let mut scratch = EvaluatedTree::new();
+    let root = emit(p, &mut scratch);
+    let mut out = EvaluatedTree::new();
+    scratch.copy_into(&mut out, root);
+    out
+}
+
+fn emit(p: &Poly, to: &mut EvaluatedTree) -> ExpressionRef {
+    let mut acc: Option<ExpressionRef> = None;
+    let mut constant = None;
+    for (key, term) in p.terms.iter() {
+        if key.is_empty() {
+            constant = Some(term.coefficient);
+            continue;
+        }
+        acc = Some(match acc {
+            None => emit_term(term, term.coefficient, to),
+            Some(acc) => {
+                let builtin = if term.coefficient.is_negative() { Builtin::Sub } else { Builtin::Add };
+                let t = emit_term(term, term.coefficient.abs(), to);
+                to.push(EE::Builtin{builtin, args: vec![acc, t]})
+            }
+        });
+    }
+    match (acc, constant) {
+        (None, None) => to.push(EE::C(Primitive::from(0))),
+        (None, Some(c)) => to.push(EE::C(c.to_primitive())),
+        (Some(acc), None) => acc,
+        (Some(acc), Some(c)) => {
+            let builtin = if c.is_negative() { Builtin::Sub } else { Builtin::Add };
+            let c = to.push(EE::C(c.abs().to_primitive()));
+            to.push(EE::Builtin{builtin, args: vec![acc, c]})
+        }
+    }
+}
+
+fn emit_term(term: &Term, coefficient: Number, to: &mut EvaluatedTree) -> ExpressionRef {
+    let mut product: Option<ExpressionRef> = None;
+    for (_, atom, power) in term.factors.iter() {
+        let mut factor = match atom {
+            Atom::Var(id) => to.push(EE::VarRef(id.clone())),
+            Atom::Opaque(t) => t.copy_into(to, t.root()),
+        };
+        // repeated factors stay products, as `pow` evaluates differently
+        for _ in 1..*power {
+            let again = match atom {
+                Atom::Var(id) => to.push(EE::VarRef(id.clone())),
+                Atom::Opaque(t) => t.copy_into(to, t.root()),
+            };
+            factor = to.push(EE::Builtin{builtin: Builtin::Mul, args: vec![factor, again]});
+        }
+        product = Some(match product {
+            None => factor,
+            Some(p) => to.push(EE::Builtin{builtin: Builtin::Mul, args: vec![p, factor]}),
+        });
+    }
+    let product = product.unwrap();
+    if coefficient.is_one() {
+        product
+    } else {
+        let c = to.push(EE::C(coefficient.to_primitive()));
+        to.push(EE::Builtin{builtin: Builtin::Mul, args: vec![c, product]})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decompile::{constraint_source, decompile_tree};
+
+    // every constraint of a program, as it reads after simplifying
+    fn constraints(program: &str) -> Vec<String> {
+        crate::compile(program).constraints.iter().map(constraint_source).collect()
+    }
+
+    const INTEGERS: &str = "(let [x (decision (int-range 0 5)) y (decision (int-range 0 5))]";
+    const FLOATS: &str = "(let [a (sample (map-categorical (vector 1 1) (vector 0.1 0.2)))
+                                b (sample (map-categorical (vector 1 1) (vector 0.2 0.1)))
+                                x (decision (int-range 0 5))]";
+
+    #[test]
+    fn integer_constraints_are_canonical() {
+        let program = format!("{} (begin
+            (constrain >= (+ (* 2 x) (- 3 y)) (+ y 1))
+            (constrain < (+ x 0) (* 1 7))
+            (constrain <= (- 4 (* 3 x)) y)
+            (constrain = (+ x y) (+ y 2))))", INTEGERS);
+        assert_eq!(constraints(&program), [
+            "(constrain ≥ (- (* 2 @D0) (* 2 @D1)) -2)",
+            "(constrain < @D0 7)",
+            "(constrain ≥ (+ (* 3 @D0) @D1) 4)",
+            "(constrain = @D0 2)",
+        ]);
+    }
+
+    #[test]
+    fn repeated_factors_stay_products() {
+        let program = format!("{} (begin (constrain <= (* x x) 9) (constrain <= (pow y 3) 8)))", INTEGERS);
+        assert_eq!(constraints(&program), [
+            "(constrain ≤ (* @D0 @D0) 9)",
+            "(constrain ≤ (* (* @D1 @D1) @D1) 8)",
+        ]);
+    }
+
+    #[test]
+    fn float_constraints_keep_their_shape() {
+        // (0.1 + 0.2) + x and 0.1 + (0.2 + x) round differently
+        let program = format!("{} (begin
+            (constrain <= (+ (+ a b) x) 0.3)
+            (constrain = (- a 0.1) b)
+            (constrain > (/ x 4) a)))", FLOATS);
+        assert_eq!(constraints(&program), [
+            "(constrain ≤ (+ (+ @S0 @S1) @D2) 0.3)",
+            "(constrain = (- @S0 0.1) @S1)",
+            "(constrain > (/ @D2 4) @S0)",
+        ]);
+    }
+
+    #[test]
+    fn float_bodies_keep_their_shape() {
+        let program = format!("{} (+ (* a x) (+ b (+ x (+ 1 x)))))", FLOATS);
+        let graph = crate::compile(&program);
+        // the integer subtree is still simplified
+        assert_eq!(decompile_tree(&graph.body), "(begin\n  (+ (* @S0 @D2) (+ @S1 (+ (* 2 @D2) 1))))\n");
+    }
+}
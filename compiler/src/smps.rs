@@ -0,0 +1,1006 @@
+use crate::graph::{compile_graph, GraphError, EE};
+use common::*;
+use primitives::{Distribution, Domain, Primitive};
+use std::collections::HashMap;
+
+
+// how far probabilities may sum from 1 before a file is refused
+const PROBABILITY_TOLERANCE: f64 = 1e-6;
+
+/// How the columns of an SMPS core file are turned into decisions. Decisions range
+/// over finite domains, so continuous columns are discretized onto a grid.
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    // how many evenly spaced values a continuous column can take
+    pub grid: usize,
+    // how far a column with an infinite bound may range from its other bound, or
+    // from 0 if it has neither; without it such columns are refused
+    pub bound: Option<f64>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions{grid: 11, bound: None}
+    }
+}
+
+pub struct Import {
+    pub graph: ScpGraph,
+    // what each variable stands for in the SMPS files, in binding order
+    pub names: Vec<(Identifier, String)>,
+}
+
+#[derive(Debug)]
+pub enum SmpsError {
+    Parse{file: &'static str, line: usize, message: String},
+    // valid SMPS outside of the discrete subset
+    Unsupported(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for SmpsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse{file, line, message} => write!(f, "{} file, line {}: {}", file, line, message),
+            Self::Unsupported(what) => write!(f, "{} is not supported", what),
+            Self::Invalid(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+/// Reads the discrete-scenario subset of SMPS and builds the graph the equivalent
+/// program would compile to. Columns become decisions and rows constraints; every
+/// `INDEP` entry becomes a `map-categorical` variable, every `BLOCKS` block a
+/// `categorical` over its realizations, and every period of a `SCENARIOS` tree a
+/// variable holding the scenario tree node reached in that period. The time file
+/// sets the stages: the random entries of a period are revealed before its columns
+/// are decided.
+pub fn import(core: &str, time: &str, stoch: &str, options: &ImportOptions) -> Result<Import, SmpsError> {
+    let core = parse_core(core)?;
+    let time = parse_time(time, &core)?;
+    let stoch = parse_stoch(stoch, &core, &time)?;
+    build(&core, &time, &stoch, options)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RowType {
+    // an `N` row other than the objective, which constrains nothing
+    Free,
+    Le,
+    Ge,
+    Eq,
+}
+
+struct Column {
+    name: String,
+    integer: bool,
+    lo: f64,
+    hi: f64,
+}
+
+// a coefficient `(Some(column), row)` or a right-hand side `(None, row)`
+type Element = (Option<usize>, usize);
+
+#[derive(Default)]
+struct Core {
+    rows: Vec<(String, RowType)>,
+    row_index: HashMap<String, usize>,
+    columns: Vec<Column>,
+    column_index: HashMap<String, usize>,
+    values: HashMap<Element, f64>,
+    ranges: HashMap<usize, f64>,
+    rhs_name: Option<String>,
+    objective: Option<usize>,
+    maximize: bool,
+}
+
+impl Core {
+    // the element an SMPS `column row` pair names; any name that isn't a column is
+    // taken as the right-hand side when the core file doesn't name its own
+    fn element(&self, column: &str, row: &str) -> Result<Element, String> {
+        let row = *self.row_index.get(row).ok_or_else(|| format!("unknown row {}", row))?;
+        match self.column_index.get(column) {
+            Some(c) => Ok((Some(*c), row)),
+            None if self.rhs_name.as_deref().is_none_or(|n| n == column) => Ok((None, row)),
+            None => Err(format!("unknown column {}", column)),
+        }
+    }
+
+    fn element_name(&self, (column, row): Element) -> String {
+        let column = column.map_or("RHS", |c| self.columns[c].name.as_str());
+        format!("{}/{}", column, self.rows[row].0)
+    }
+
+    fn value(&self, element: Element) -> f64 {
+        self.values.get(&element).copied().unwrap_or(0.)
+    }
+}
+
+struct Line<'a> {
+    number: usize,
+    // section headers start in the first column, data lines are indented
+    header: bool,
+    tokens: Vec<&'a str>,
+}
+
+fn lines(text: &str) -> impl Iterator<Item = Line<'_>> {
+    text.lines().enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('*'))
+        .map(|(i, l)| Line{
+            number: i + 1,
+            header: !l.starts_with(char::is_whitespace),
+            tokens: l.split_whitespace().collect(),
+        })
+}
+
+fn parse_error(file: &'static str, line: &Line, message: String) -> SmpsError {
+    SmpsError::Parse{file, line: line.number, message}
+}
+
+fn number(file: &'static str, line: &Line, token: &str) -> Result<f64, SmpsError> {
+    token.parse().map_err(|_| parse_error(file, line, format!("expected a number, found {}", token)))
+}
+
+fn parse_core(text: &str) -> Result<Core, SmpsError> {
+    let mut core = Core::default();
+    let mut section = "";
+    let mut integer = false;
+    let maximize = |s: &str| matches!(s, "MAX" | "MAXIMIZE");
+
+    for line in lines(text) {
+        let tokens = &line.tokens;
+        let fail = |message: String| parse_error("core", &line, message);
+        if line.header {
+            section = tokens[0];
+            match section {
+                "NAME" | "ROWS" | "COLUMNS" | "RHS" | "RANGES" | "BOUNDS" => (),
+                "OBJSENSE" => core.maximize = tokens.get(1).is_some_and(|s| maximize(s)),
+                "ENDATA" => break,
+                _ => return Err(SmpsError::Unsupported(format!("the {} section of a core file", section))),
+            }
+            continue;
+        }
+
+        match section {
+            "OBJSENSE" => core.maximize = maximize(tokens[0]),
+            "ROWS" => {
+                if tokens.len() != 2 {
+                    return Err(fail(String::from("expected a row type and name")));
+                }
+                let kind = match tokens[0] {
+                    "N" if core.objective.is_none() => {
+                        core.objective = Some(core.rows.len());
+                        RowType::Free
+                    }
+                    "N" => RowType::Free,
+                    "L" => RowType::Le,
+                    "G" => RowType::Ge,
+                    "E" => RowType::Eq,
+                    other => return Err(fail(format!("unknown row type {}", other))),
+                };
+                core.row_index.insert(tokens[1].to_string(), core.rows.len());
+                core.rows.push((tokens[1].to_string(), kind));
+            }
+            "COLUMNS" => {
+                if tokens.get(1) == Some(&"'MARKER'") {
+                    match tokens.get(2) {
+                        Some(&"'INTORG'") => integer = true,
+                        Some(&"'INTEND'") => integer = false,
+                        _ => return Err(fail(String::from("expected 'INTORG' or 'INTEND'"))),
+                    }
+                    continue;
+                }
+                if tokens.len() < 3 || tokens.len() % 2 == 0 {
+                    return Err(fail(String::from("expected a column name and row/value pairs")));
+                }
+                let column = match core.column_index.get(tokens[0]) {
+                    Some(c) => *c,
+                    None => {
+                        core.column_index.insert(tokens[0].to_string(), core.columns.len());
+                        core.columns.push(Column{name: tokens[0].to_string(), integer, lo: 0., hi: f64::INFINITY});
+                        core.columns.len() - 1
+                    }
+                };
+                for pair in tokens[1..].chunks(2) {
+                    let row = *core.row_index.get(pair[0]).ok_or_else(|| fail(format!("unknown row {}", pair[0])))?;
+                    core.values.insert((Some(column), row), number("core", &line, pair[1])?);
+                }
+            }
+            "RHS" | "RANGES" => {
+                // the vector's name is optional
+                let pairs = if tokens.len() % 2 == 1 {
+                    if section == "RHS" && core.rhs_name.is_none() {
+                        core.rhs_name = Some(tokens[0].to_string());
+                    }
+                    &tokens[1..]
+                } else {
+                    &tokens[..]
+                };
+                for pair in pairs.chunks(2) {
+                    let row = *core.row_index.get(pair[0]).ok_or_else(|| fail(format!("unknown row {}", pair[0])))?;
+                    let value = number("core", &line, pair[1])?;
+                    if section == "RHS" {
+                        core.values.insert((None, row), value);
+                    } else {
+                        core.ranges.insert(row, value);
+                    }
+                }
+            }
+            "BOUNDS" => {
+                // the bound set's name is optional, so find the column by name
+                let rest = &tokens[1..];
+                let at = if rest.len() > 1 && core.column_index.contains_key(rest[1]) { 1 } else { 0 };
+                let name = rest.get(at).ok_or_else(|| fail(String::from("expected a column name")))?;
+                let column = *core.column_index.get(*name).ok_or_else(|| fail(format!("unknown column {}", name)))?;
+                let value = match rest.get(at + 1) {
+                    Some(v) => Some(number("core", &line, v)?),
+                    None => None,
+                };
+                let value = || value.ok_or_else(|| fail(format!("{} bound needs a value", tokens[0])));
+                let c = &mut core.columns[column];
+                match tokens[0] {
+                    "UP" => {
+                        c.hi = value()?;
+                        // MPS convention: a negative upper bound frees the default lower bound
+                        if c.hi < 0. && c.lo == 0. {
+                            c.lo = f64::NEG_INFINITY;
+                        }
+                    }
+                    "LO" => c.lo = value()?,
+                    "FX" => {
+                        c.lo = value()?;
+                        c.hi = c.lo;
+                    }
+                    "FR" => {
+                        c.lo = f64::NEG_INFINITY;
+                        c.hi = f64::INFINITY;
+                    }
+                    "MI" => c.lo = f64::NEG_INFINITY,
+                    "PL" => c.hi = f64::INFINITY,
+                    "BV" => {
+                        c.integer = true;
+                        c.lo = 0.;
+                        c.hi = 1.;
+                    }
+                    "LI" => {
+                        c.integer = true;
+                        c.lo = value()?;
+                    }
+                    "UI" => {
+                        c.integer = true;
+                        c.hi = value()?;
+                    }
+                    other => return Err(SmpsError::Unsupported(format!("the {} bound type", other))),
+                }
+            }
+            _ => return Err(fail(String::from("data outside of a section"))),
+        }
+    }
+
+    if core.rows.is_empty() {
+        return Err(SmpsError::Invalid(String::from("the core file has no rows")));
+    }
+    Ok(core)
+}
+
+struct Time {
+    periods: Vec<String>,
+    row_period: Vec<usize>,
+    column_period: Vec<usize>,
+}
+
+impl Time {
+    fn period(&self, name: &str) -> Option<usize> {
+        self.periods.iter().position(|p| p == name)
+    }
+
+    // the period in which an element is first used
+    fn element_period(&self, (column, row): Element) -> usize {
+        let column = column.map_or(0, |c| self.column_period[c]);
+        column.max(self.row_period[row])
+    }
+}
+
+// Reads both the implicit form, where each period is given by its first column and
+// row in core order, and the explicit form, which lists every row and column.
+fn parse_time(text: &str, core: &Core) -> Result<Time, SmpsError> {
+    let mut time = Time{
+        periods: Vec::new(),
+        row_period: vec![0; core.rows.len()],
+        column_period: vec![0; core.columns.len()],
+    };
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    let mut explicit = false;
+    let mut section = "";
+
+    for line in lines(text) {
+        let tokens = &line.tokens;
+        let fail = |message: String| parse_error("time", &line, message);
+        if line.header {
+            section = tokens[0];
+            match section {
+                "TIME" => (),
+                "PERIODS" => explicit = tokens.get(1) == Some(&"EXPLICIT"),
+                "ROWS" | "COLUMNS" if explicit => (),
+                "ENDATA" => break,
+                _ => return Err(SmpsError::Unsupported(format!("the {} section of a time file", section))),
+            }
+            continue;
+        }
+
+        match section {
+            "PERIODS" if explicit => time.periods.push(tokens[0].to_string()),
+            "PERIODS" => {
+                if tokens.len() != 3 {
+                    return Err(fail(String::from("expected a column, a row and a period")));
+                }
+                let column = *core.column_index.get(tokens[0]).ok_or_else(|| fail(format!("unknown column {}", tokens[0])))?;
+                let row = *core.row_index.get(tokens[1]).ok_or_else(|| fail(format!("unknown row {}", tokens[1])))?;
+                if let Some((c, r)) = starts.last() {
+                    if column < *c || row < *r {
+                        return Err(fail(format!("period {} starts before the period it follows", tokens[2])));
+                    }
+                }
+                starts.push((column, row));
+                time.periods.push(tokens[2].to_string());
+            }
+            "ROWS" | "COLUMNS" => {
+                if tokens.len() != 2 {
+                    return Err(fail(String::from("expected a name and a period")));
+                }
+                let period = time.period(tokens[1]).ok_or_else(|| fail(format!("unknown period {}", tokens[1])))?;
+                let (index, periods) = if section == "ROWS" {
+                    (&core.row_index, &mut time.row_period)
+                } else {
+                    (&core.column_index, &mut time.column_period)
+                };
+                let at = *index.get(tokens[0]).ok_or_else(|| fail(format!("unknown name {}", tokens[0])))?;
+                periods[at] = period;
+            }
+            _ => return Err(fail(String::from("data outside of a section"))),
+        }
+    }
+
+    if time.periods.is_empty() {
+        return Err(SmpsError::Invalid(String::from("the time file defines no periods")));
+    }
+    // everything before the first period's start, like the objective, is in it
+    for (period, (column, row)) in starts.iter().enumerate() {
+        for p in time.column_period[*column..].iter_mut() {
+            *p = period;
+        }
+        for p in time.row_period[*row..].iter_mut() {
+            *p = period;
+        }
+    }
+    Ok(time)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Modifier {
+    Replace,
+    Add,
+    Multiply,
+}
+
+impl Modifier {
+    fn apply(self, core: f64, value: f64) -> f64 {
+        match self {
+            Modifier::Replace => value,
+            Modifier::Add => core + value,
+            Modifier::Multiply => core * value,
+        }
+    }
+}
+
+// one independently distributed element
+struct Indep {
+    element: Element,
+    period: usize,
+    modifier: Modifier,
+    // (value, probability)
+    outcomes: Vec<(f64, f64)>,
+}
+
+// elements that take their values together
+struct Block {
+    name: String,
+    period: usize,
+    modifier: Modifier,
+    // (probability, the values set by this realization)
+    realizations: Vec<(f64, Vec<(Element, f64)>)>,
+}
+
+// one path through a scenario tree; it follows its parent up to `period`, and
+// from there on differs by `values`
+struct Scenario {
+    name: String,
+    parent: Option<usize>,
+    probability: f64,
+    period: usize,
+    values: Vec<(Element, f64)>,
+}
+
+#[derive(Default)]
+struct Stoch {
+    indep: Vec<Indep>,
+    blocks: Vec<Block>,
+    scenarios: Vec<Scenario>,
+}
+
+fn parse_stoch(text: &str, core: &Core, time: &Time) -> Result<Stoch, SmpsError> {
+    let mut stoch = Stoch::default();
+    let mut section = "";
+    let mut modifier = Modifier::Replace;
+
+    for line in lines(text) {
+        let tokens = &line.tokens;
+        let fail = |message: String| parse_error("stoch", &line, message);
+        let element = |column: &str, row: &str| core.element(column, row).map_err(fail);
+        let period = |name: &str| time.period(name).ok_or_else(|| fail(format!("unknown period {}", name)));
+        if line.header {
+            section = tokens[0];
+            match section {
+                "STOCH" => (),
+                "ENDATA" => break,
+                "INDEP" | "BLOCKS" | "SCENARIOS" => {
+                    let distribution = tokens.get(1).copied().unwrap_or("DISCRETE");
+                    if distribution != "DISCRETE" {
+                        return Err(SmpsError::Unsupported(format!("{} {} (only discrete distributions can be imported)", section, distribution)));
+                    }
+                    modifier = match tokens.get(2).copied().unwrap_or("REPLACE") {
+                        "REPLACE" => Modifier::Replace,
+                        "ADD" if section != "SCENARIOS" => Modifier::Add,
+                        "MULTIPLY" if section != "SCENARIOS" => Modifier::Multiply,
+                        other => return Err(SmpsError::Unsupported(format!("{} with {}", section, other))),
+                    };
+                }
+                _ => return Err(SmpsError::Unsupported(format!("the {} section of a stoch file", section))),
+            }
+            continue;
+        }
+
+        match section {
+            "INDEP" => {
+                let (value, at, probability) = match tokens.len() {
+                    4 => (tokens[2], None, tokens[3]),
+                    5 => (tokens[2], Some(period(tokens[3])?), tokens[4]),
+                    _ => return Err(fail(String::from("expected a column, a row, a value, an optional period and a probability"))),
+                };
+                let element = element(tokens[0], tokens[1])?;
+                let outcome = (number("stoch", &line, value)?, number("stoch", &line, probability)?);
+                match stoch.indep.iter_mut().find(|i| i.element == element) {
+                    Some(indep) => indep.outcomes.push(outcome),
+                    None => stoch.indep.push(Indep{
+                        element,
+                        period: at.unwrap_or_else(|| time.element_period(element)),
+                        modifier,
+                        outcomes: vec![outcome],
+                    }),
+                }
+            }
+            "BLOCKS" if tokens[0] == "BL" => {
+                if tokens.len() != 4 {
+                    return Err(fail(String::from("expected BL, a block name, a period and a probability")));
+                }
+                let at = period(tokens[2])?;
+                let probability = number("stoch", &line, tokens[3])?;
+                let block = match stoch.blocks.iter().position(|b| b.name == tokens[1]) {
+                    Some(b) if stoch.blocks[b].period != at => {
+                        return Err(fail(format!("block {} is revealed in two periods", tokens[1])));
+                    }
+                    Some(b) => b,
+                    None => {
+                        stoch.blocks.push(Block{name: tokens[1].to_string(), period: at, modifier, realizations: Vec::new()});
+                        stoch.blocks.len() - 1
+                    }
+                };
+                stoch.blocks[block].realizations.push((probability, Vec::new()));
+            }
+            "SCENARIOS" if tokens[0] == "SC" => {
+                if tokens.len() != 5 {
+                    return Err(fail(String::from("expected SC, a scenario name, its parent, a probability and a period")));
+                }
+                let parent = match tokens[2].trim_matches('\'') {
+                    "ROOT" => None,
+                    name => Some(stoch.scenarios.iter().position(|s| s.name == name)
+                        .ok_or_else(|| fail(format!("unknown parent scenario {}", name)))?),
+                };
+                stoch.scenarios.push(Scenario{
+                    name: tokens[1].to_string(),
+                    parent,
+                    probability: number("stoch", &line, tokens[3])?,
+                    period: period(tokens[4])?,
+                    values: Vec::new(),
+                });
+            }
+            "BLOCKS" | "SCENARIOS" => {
+                if tokens.len() != 3 {
+                    return Err(fail(String::from("expected a column, a row and a value")));
+                }
+                let entry = (element(tokens[0], tokens[1])?, number("stoch", &line, tokens[2])?);
+                let values = if section == "BLOCKS" {
+                    stoch.blocks.last_mut().and_then(|b| b.realizations.last_mut()).map(|r| &mut r.1)
+                } else {
+                    stoch.scenarios.last_mut().map(|s| &mut s.values)
+                };
+                values.ok_or_else(|| fail(String::from("value before the first realization")))?.push(entry);
+            }
+            _ => return Err(fail(String::from("data outside of a section"))),
+        }
+    }
+
+    let check = |what: String, total: f64| {
+        if (total - 1.).abs() > PROBABILITY_TOLERANCE {
+            Err(SmpsError::Invalid(format!("the probabilities of {} sum to {}, not 1", what, total)))
+        } else {
+            Ok(())
+        }
+    };
+    for indep in stoch.indep.iter() {
+        check(core.element_name(indep.element), indep.outcomes.iter().map(|o| o.1).sum())?;
+    }
+    for block in stoch.blocks.iter() {
+        check(format!("block {}", block.name), block.realizations.iter().map(|r| r.0).sum())?;
+    }
+    if !stoch.scenarios.is_empty() {
+        check(String::from("the scenarios"), stoch.scenarios.iter().map(|s| s.probability).sum())?;
+    }
+    for s in stoch.scenarios.iter() {
+        if let Some(p) = s.parent {
+            if s.period < stoch.scenarios[p].period {
+                return Err(SmpsError::Invalid(format!("scenario {} branches before its parent {}", s.name, stoch.scenarios[p].name)));
+            }
+        }
+        for (element, _) in s.values.iter() {
+            if time.element_period(*element) < s.period {
+                return Err(SmpsError::Invalid(format!("scenario {} sets {} before it branches", s.name, core.element_name(*element))));
+            }
+        }
+    }
+    Ok(stoch)
+}
+
+// every node of one period, by owner, with its probability
+type Nodes = Vec<(Option<usize>, f64)>;
+
+// The scenario tree of a `SCENARIOS` section, one level per period. A node is named
+// by the scenario that owns it: the one whose values hold at that period, which is
+// the first scenario on the path back to the root to have branched by then.
+struct ScenarioNodes<'a> {
+    scenarios: &'a [Scenario],
+    // the first period anything branches in
+    first: usize,
+}
+
+impl<'a> ScenarioNodes<'a> {
+    // `None` is the root, which holds the core values
+    fn owner(&self, mut s: Option<usize>, period: usize) -> Option<usize> {
+        while let Some(i) = s {
+            if self.scenarios[i].period <= period {
+                break;
+            }
+            s = self.scenarios[i].parent;
+        }
+        s
+    }
+
+    // every node in a period with its probability, in scenario order
+    fn nodes(&self, period: usize) -> Nodes {
+        let mut nodes: Nodes = Vec::new();
+        for (i, s) in self.scenarios.iter().enumerate() {
+            let owner = self.owner(Some(i), period);
+            match nodes.iter_mut().find(|n| n.0 == owner) {
+                Some(n) => n.1 += s.probability,
+                None => nodes.push((owner, s.probability)),
+            }
+        }
+        nodes
+    }
+
+    fn value(&self, mut s: Option<usize>, element: Element, core: &Core) -> f64 {
+        while let Some(i) = s {
+            if let Some((_, v)) = self.scenarios[i].values.iter().find(|(e, _)| *e == element) {
+                return *v;
+            }
+            s = self.scenarios[i].parent;
+        }
+        core.value(element)
+    }
+}
+
+struct Builder {
+    tree: EvaluatedTree,
+    // the variables bound so far, in order
+    forms: Vec<ExpressionRef>,
+    names: Vec<(Identifier, String)>,
+}
+
+impl Builder {
+    fn constant(&mut self, p: Primitive) -> ExpressionRef {
+        self.tree.push(EE::C(p))
+    }
+
+    fn call(&mut self, builtin: Builtin, args: Vec<ExpressionRef>) -> ExpressionRef {
+        self.tree.push(EE::Builtin{builtin, args})
+    }
+
+    // fresh ids share one counter so that binding order, and with it the stages,
+    // follows the periods
+    fn bind(&mut self, kind: VariableKind, body: ExpressionRef, name: String) -> ExpressionRef {
+        let prefix = match kind {
+            VariableKind::Decision => "@D",
+            VariableKind::Stochastic => "@S",
+        };
+        let id = Identifier::from(format!("{}{}", prefix, self.forms.len()));
+        let var = match kind {
+            VariableKind::Decision => self.tree.push(EE::Decision{id: id.clone(), body}),
+            VariableKind::Stochastic => self.tree.push(EE::Stochastic{id: id.clone(), body}),
+        };
+        self.forms.push(var);
+        self.names.push((id, name));
+        var
+    }
+
+    // a balanced sum, so that long rows don't nest deeply
+    fn sum(&mut self, terms: &[ExpressionRef]) -> ExpressionRef {
+        match terms.len() {
+            0 => self.constant(Primitive::Float(0.)),
+            1 => terms[0],
+            n => {
+                let left = self.sum(&terms[..n / 2]);
+                let right = self.sum(&terms[n / 2..]);
+                self.call(Builtin::Add, vec![left, right])
+            }
+        }
+    }
+
+    // `(get (vector values…) index)`
+    fn select(&mut self, values: Vec<f64>, index: ExpressionRef) -> ExpressionRef {
+        let values = self.constant(Primitive::Vector(values.into_iter().map(Primitive::Float).collect()));
+        self.call(Builtin::Get, vec![values, index])
+    }
+}
+
+fn categorical(weights: Vec<f64>, values: Option<Vec<Primitive>>) -> Primitive {
+    let weights = weights.into_iter().map(|w| w as f32).collect();
+    Primitive::Distribution(match values {
+        Some(values) => Distribution::MappedCategorical{weights, values},
+        None => Distribution::Categorical{weights},
+    })
+}
+
+fn domain(column: &Column, options: &ImportOptions) -> Result<Domain, SmpsError> {
+    let unbounded = |side: &str| SmpsError::Invalid(format!(
+        "column {} has no finite {} bound, and no default bound was given", column.name, side));
+    let (lo, hi) = match (column.lo.is_finite(), column.hi.is_finite(), options.bound) {
+        (true, true, _) => (column.lo, column.hi),
+        (true, false, Some(b)) => (column.lo, column.lo.max(0.) + b),
+        (false, true, Some(b)) => (column.hi.min(0.) - b, column.hi),
+        (false, false, Some(b)) => (-b, b),
+        (false, _, None) => return Err(unbounded("lower")),
+        (true, false, None) => return Err(unbounded("upper")),
+    };
+    if lo > hi {
+        return Err(SmpsError::Invalid(format!("column {} has bounds {} > {}", column.name, lo, hi)));
+    }
+
+    if column.integer {
+        let (lo, hi) = (lo.ceil() as i128, hi.floor() as i128);
+        if lo > hi {
+            return Err(SmpsError::Invalid(format!("integer column {} has no integer between its bounds", column.name)));
+        }
+        Ok(Domain::IntRange(lo, hi + 1))
+    } else if lo == hi || options.grid < 2 {
+        Ok(Domain::OneOf(vec![Primitive::Float(lo)]))
+    } else {
+        let step = (hi - lo) / (options.grid - 1) as f64;
+        Ok(Domain::OneOf((0..options.grid).map(|i| Primitive::Float(lo + step * i as f64)).collect()))
+    }
+}
+
+fn build(core: &Core, time: &Time, stoch: &Stoch, options: &ImportOptions) -> Result<Import, SmpsError> {
+    let mut b = Builder{tree: EvaluatedTree::new(), forms: Vec::new(), names: Vec::new()};
+    let root = b.tree.placeholder();
+
+    let nodes = ScenarioNodes{
+        scenarios: &stoch.scenarios,
+        first: stoch.scenarios.iter().map(|s| s.period).min().unwrap_or(usize::MAX),
+    };
+    let first_random = stoch.indep.iter().map(|i| i.period)
+        .chain(stoch.blocks.iter().map(|b| b.period))
+        .chain(std::iter::once(nodes.first))
+        .min()
+        .unwrap();
+    if first_random == 0 {
+        return Err(SmpsError::Invalid(format!("the first period, {}, must be deterministic", time.periods[0])));
+    }
+
+    // the expression for every random element
+    let mut random: HashMap<Element, ExpressionRef> = HashMap::new();
+    let mut set = |element: Element, expr: ExpressionRef| match random.insert(element, expr) {
+        Some(_) => Err(SmpsError::Invalid(format!("{} is random in more than one place", core.element_name(element)))),
+        None => Ok(()),
+    };
+    let mut columns = vec![None; core.columns.len()];
+    // the scenario tree node variable of the previous period
+    let mut previous: Option<(ExpressionRef, Nodes)> = None;
+
+    for (period, period_name) in time.periods.iter().enumerate() {
+        for indep in stoch.indep.iter().filter(|i| i.period == period) {
+            let base = core.value(indep.element);
+            let values = indep.outcomes.iter().map(|(v, _)| Primitive::Float(indep.modifier.apply(base, *v))).collect();
+            let weights = indep.outcomes.iter().map(|(_, p)| *p).collect();
+            let definition = b.constant(categorical(weights, Some(values)));
+            let var = b.bind(VariableKind::Stochastic, definition, core.element_name(indep.element));
+            set(indep.element, var)?;
+        }
+
+        for block in stoch.blocks.iter().filter(|b| b.period == period) {
+            let weights = block.realizations.iter().map(|r| r.0).collect();
+            let definition = b.constant(categorical(weights, None));
+            let var = b.bind(VariableKind::Stochastic, definition, format!("block {}", block.name));
+            let mut elements: Vec<Element> = block.realizations.iter().flat_map(|r| r.1.iter().map(|(e, _)| *e)).collect();
+            elements.sort();
+            elements.dedup();
+            for element in elements {
+                let base = core.value(element);
+                let values = block.realizations.iter()
+                    .map(|(_, values)| match values.iter().find(|(e, _)| *e == element) {
+                        Some((_, v)) => block.modifier.apply(base, *v),
+                        None => base,
+                    })
+                    .collect();
+                let expr = b.select(values, var);
+                set(element, expr)?;
+            }
+        }
+
+        if period >= nodes.first {
+            let here = nodes.nodes(period);
+            // the node of this period's variable is an index into `here`, and the
+            // distribution of its children depends on the previous period's node
+            let children = |parent: Option<&(Option<usize>, f64)>| {
+                let (weights, values) = here.iter().enumerate()
+                    .filter(|(_, (owner, _))| parent.is_none_or(|(p, _)| nodes.owner(*owner, period - 1) == *p))
+                    .map(|(i, (_, mass))| (mass / parent.map_or(1., |(_, m)| *m), Primitive::Int(i as i128)))
+                    .unzip();
+                categorical(weights, Some(values))
+            };
+            let definition = match &previous {
+                None => b.constant(children(None)),
+                Some((var, before)) => {
+                    let table = b.constant(Primitive::Vector(before.iter().map(|n| children(Some(n))).collect()));
+                    b.call(Builtin::Get, vec![table, *var])
+                }
+            };
+            let var = b.bind(VariableKind::Stochastic, definition, format!("scenario tree node in {}", period_name));
+
+            let mut elements: Vec<Element> = stoch.scenarios.iter()
+                .flat_map(|s| s.values.iter().map(|(e, _)| *e))
+                .filter(|e| time.element_period(*e) == period)
+                .collect();
+            elements.sort();
+            elements.dedup();
+            for element in elements {
+                let values = here.iter().map(|(owner, _)| nodes.value(*owner, element, core)).collect();
+                let expr = b.select(values, var);
+                set(element, expr)?;
+            }
+            previous = Some((var, here));
+        }
+
+        for (c, column) in core.columns.iter().enumerate().filter(|(c, _)| time.column_period[*c] == period) {
+            let definition = b.constant(Primitive::Domain(domain(column, options)?));
+            columns[c] = Some(b.bind(VariableKind::Decision, definition, column.name.clone()));
+        }
+    }
+
+    // every row's terms, in column order
+    let mut terms: Vec<Vec<usize>> = vec![Vec::new(); core.rows.len()];
+    for (column, row) in core.values.keys().chain(random.keys()) {
+        if let Some(c) = column {
+            terms[*row].push(*c);
+        }
+    }
+    let mut row_sums = Vec::with_capacity(core.rows.len());
+    for (row, columns_in_row) in terms.iter_mut().enumerate() {
+        columns_in_row.sort_unstable();
+        columns_in_row.dedup();
+        let mut products = Vec::with_capacity(columns_in_row.len());
+        for c in columns_in_row.iter() {
+            let coefficient = element_expr(&mut b, core, &random, (Some(*c), row));
+            products.push(b.call(Builtin::Mul, vec![coefficient, columns[*c].unwrap()]));
+        }
+        row_sums.push(b.sum(&products));
+    }
+
+    let mut forms = std::mem::take(&mut b.forms);
+    for (row, (_, kind)) in core.rows.iter().enumerate() {
+        let relations: &[Relation] = match kind {
+            RowType::Free => &[],
+            RowType::Le => &[Relation::Leq],
+            RowType::Ge => &[Relation::Geq],
+            RowType::Eq => &[Relation::Eq],
+        };
+        let rhs = element_expr(&mut b, core, &random, (None, row));
+        for relation in relations {
+            forms.push(b.tree.push(EE::Constrain{prob: 1., relation: *relation, left: row_sums[row], right: rhs}));
+        }
+
+        // a range turns the row into `lower ≤ row ≤ upper`, with the bound given
+        // by the rhs kept
+        let range = match core.ranges.get(&row) {
+            Some(r) if *kind != RowType::Free && *r != 0. => *r,
+            _ => continue,
+        };
+        let (relation, offset) = match kind {
+            RowType::Le => (Relation::Geq, -range.abs()),
+            RowType::Ge => (Relation::Leq, range.abs()),
+            _ if range > 0. => (Relation::Leq, range),
+            _ => (Relation::Geq, range),
+        };
+        let offset = b.constant(Primitive::Float(offset));
+        let bound = b.call(Builtin::Add, vec![rhs, offset]);
+        forms.push(b.tree.push(EE::Constrain{prob: 1., relation, left: row_sums[row], right: bound}));
+    }
+
+    // the objective's rhs is the negated objective constant
+    let body = match core.objective {
+        Some(row) => {
+            let constant = element_expr(&mut b, core, &random, (None, row));
+            let cost = b.call(Builtin::Sub, vec![row_sums[row], constant]);
+            if core.maximize {
+                let minus_one = b.constant(Primitive::Float(-1.));
+                b.call(Builtin::Mul, vec![minus_one, cost])
+            } else {
+                cost
+            }
+        }
+        None => b.call(Builtin::Nil, Vec::new()),
+    };
+    forms.push(body);
+    b.tree.replace(root, EE::Begin(forms));
+
+    let graph = compile_graph(&b.tree).map_err(|e| match e {
+        GraphError::Cycle(names) => SmpsError::Invalid(format!("dependency cycle through {}", names.join(", "))),
+    })?;
+    Ok(Import{graph, names: b.names})
+}
+
+fn element_expr(b: &mut Builder, core: &Core, random: &HashMap<Element, ExpressionRef>, element: Element) -> ExpressionRef {
+    match random.get(&element) {
+        Some(expr) => *expr,
+        None => b.constant(Primitive::Float(core.value(element))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x is decided first, then y and z after the right-hand sides of d2 and d3
+    const CORE: &str = "\
+NAME          three
+ROWS
+ N  obj
+ L  c1
+ G  d2
+ G  d3
+COLUMNS
+    MARKER  'MARKER'  'INTORG'
+    x    obj   1.0   c1   1.0
+    x    d2    1.0
+    MARKER  'MARKER'  'INTEND'
+    y    obj   2.0   d2   1.0
+    y    d3    1.0
+    z    obj   4.0   d3   1.0
+RHS
+    rhs  c1    3.0
+BOUNDS
+ UP bnd  x  3
+ UP bnd  y  4
+ UP bnd  z  4
+ENDATA
+";
+
+    const TIME: &str = "\
+TIME three
+PERIODS IMPLICIT
+    x   obj   T1
+    y   d2    T2
+    z   d3    T3
+ENDATA
+";
+
+    fn import_stoch(stoch: &str) -> Result<Import, SmpsError> {
+        import(CORE, TIME, stoch, &ImportOptions::default())
+    }
+
+    fn names(import: &Import) -> Vec<(&str, &str)> {
+        import.names.iter().map(|(id, name)| (id.as_str(), name.as_str())).collect()
+    }
+
+    fn objective(import: &Import) -> f64 {
+        common::solve::solve_exact(&import.graph).unwrap().objective
+    }
+
+    #[test]
+    fn indep() {
+        let import = import_stoch("\
+STOCH three
+INDEP DISCRETE
+    rhs d2 2.0 0.5
+    rhs d2 4.0 0.5
+    rhs d3 1.0 0.5
+    rhs d3 3.0 0.5
+ENDATA
+").unwrap();
+        assert_eq!(names(&import), [
+            ("@D0", "x"), ("@S1", "RHS/d2"), ("@D2", "y"), ("@S3", "RHS/d3"), ("@D4", "z"),
+        ]);
+        assert_eq!(import.graph.stages.len(), 3);
+        // x = 0, and y alone covers d2 and most of d3: (6.4 + 8) / 2
+        assert!((objective(&import) - 7.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn blocks() {
+        let import = import_stoch("\
+STOCH three
+BLOCKS DISCRETE
+ BL B1 T2 0.3
+    rhs d2 1
+    y   d3 0.5
+ BL B1 T2 0.7
+    rhs d2 2
+ENDATA
+").unwrap();
+        assert_eq!(names(&import), [("@D0", "x"), ("@S1", "block B1"), ("@D2", "y"), ("@D3", "z")]);
+        let block = import.graph.variables.get_by_name(&Identifier::from("@S1")).unwrap();
+        let distribution = common::scenario::conditional_distribution(&import.graph, block, &HashMap::new()).unwrap();
+        let outcomes = common::scenario::outcomes(&distribution).unwrap();
+        // categorical weights are stored as f32
+        assert_eq!(outcomes, [(Primitive::Int(0), 0.3f32 as f64), (Primitive::Int(1), 0.7f32 as f64)]);
+    }
+
+    #[test]
+    fn scenarios() {
+        let import = import_stoch("\
+STOCH three
+SCENARIOS DISCRETE
+ SC S1 'ROOT' 0.25 T2
+    rhs d2 4
+    rhs d3 6
+ SC S2 S1 0.25 T3
+    rhs d3 2
+ SC S3 'ROOT' 0.25 T2
+    rhs d2 6
+    rhs d3 1
+ SC S4 S3 0.25 T3
+    rhs d3 3
+ENDATA
+").unwrap();
+        assert_eq!(names(&import), [
+            ("@D0", "x"), ("@S1", "scenario tree node in T2"), ("@D2", "y"), ("@S3", "scenario tree node in T3"), ("@D4", "z"),
+        ]);
+        // the node reached in T3 depends on the one reached in T2
+        let [first, second] = ["@S1", "@S3"].map(|id| import.graph.variables.get_by_name(&Identifier::from(id)).unwrap());
+        assert!(import.graph.children[first.id as usize].contains(&second));
+        assert!((objective(&import) - 12.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn probabilities_must_sum_to_one() {
+        let result = import_stoch("\
+STOCH three
+BLOCKS DISCRETE
+ BL B1 T2 0.3
+    rhs d2 1
+ BL B1 T2 0.6
+    rhs d2 2
+ENDATA
+");
+        assert!(matches!(result, Err(SmpsError::Invalid(_))));
+    }
+}
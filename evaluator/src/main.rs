@@ -1,7 +1,5 @@
 #![allow(dead_code)]
 
-mod utilities;
-
 use common::*;
 
 mod q;
@@ -15,15 +13,95 @@ fn main() {
 
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Wrong number of arguments, expected 1, got {}", args.len() - 1);
-        std::process::exit(1);
-    }
+    let (input, seed, episodes) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let seed = random::Seed::new(seed);
+    // rerun with `--seed` to get the same policy
+    println!("Seed {}", seed);
 
-    let fpath = path::PathBuf::from(&args[1]);
+    let fpath = path::PathBuf::from(input);
     let mut file = std::fs::File::open(fpath).unwrap();
     let mut buf = Vec::<u8>::new();
     file.read_to_end(&mut buf).unwrap();
 
-    let program: ScpGraph = bincode::deserialize(&buf).unwrap();
+    let program: ScpGraph = if json::is_json(&buf) {
+        let text = match std::str::from_utf8(&buf) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Graph JSON is not valid UTF-8: {}", e);
+                std::process::exit(2);
+            }
+        };
+        match json::from_json(text) {
+            Ok(g) => g,
+            Err(e) => {
+                eprintln!("Could not read {:?}: {}", input, e);
+                std::process::exit(2);
+            }
+        }
+    } else {
+        match container::read(&buf) {
+            Ok((header, g)) => {
+                println!("Loaded graph compiled by compiler {} (format {}, source {:016x})",
+                    header.compiler_version, header.format_version, header.source_hash);
+                g
+            }
+            Err(e) => {
+                eprintln!("Could not read {:?}: {}", input, e);
+                std::process::exit(2);
+            }
+        }
+    };
+
+    println!("Learning from {} episodes…", episodes);
+    let start = now();
+    let q = match q::q_learn(&program, seed, episodes) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("Learning failed: {}", e);
+            std::process::exit(3);
+        }
+    };
+    println!("Learning took {:?}, {} states visited", start.elapsed(), q.states());
+
+    match q.first_stage(&program) {
+        Ok(decisions) => for (name, value, estimate) in decisions {
+            println!("{:>8} = {:?}: mean body {}, constraints failed in {:.1}% of {} episodes",
+                name, value, estimate.mean(), 100. * estimate.failure_rate(), estimate.visits);
+        },
+        Err(e) => {
+            eprintln!("Could not read the policy: {}", e);
+            std::process::exit(3);
+        }
+    }
+}
+
+// The graph path, the seed if given and the number of episodes:
+// `evaluator path/to/graph [--seed n] [--episodes n]`.
+fn parse_args(args: &[String]) -> Result<(&str, Option<u64>, u64), String> {
+    let mut input = None;
+    let mut seed = None;
+    let mut episodes = 10_000;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let n = iter.next().ok_or("--seed requires a number")?;
+                seed = Some(n.parse().map_err(|_| format!("Invalid seed {}", n))?);
+            }
+            "--episodes" => {
+                let n = iter.next().ok_or("--episodes requires a number")?;
+                episodes = n.parse().map_err(|_| format!("Invalid episode count {}", n))?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_none() => input = Some(arg.as_str()),
+            _ => return Err(String::from("Wrong number of arguments, expected 1")),
+        }
+    }
+    input.map(|i| (i, seed, episodes)).ok_or_else(|| String::from("Wrong number of arguments, expected 1, got 0"))
 }
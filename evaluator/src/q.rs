@@ -1,38 +1,218 @@
-use ndarray::prelude::*;
 use common::{*, primitives::*};
 use smallvec::*;
+use rand::Rng;
+use rand::rngs::StdRng;
+use std::collections::HashMap;
 
 mod variables;
 use variables::*;
 
+// chance of trying a random value instead of the best one so far
+const EPSILON: f64 = 0.1;
+// episodes run against the same table before it is updated
+const ROUND: u64 = 256;
+
+
+// What is known when a decision is made: the decision, and the support index of
+// every stochastic value revealed in the stages before it.
+type State = (u32, SmallVec<[usize; 8]>);
+
+// every decision's state, domain size and value index in one episode
+type Taken = Vec<(State, usize, usize)>;
+
+// Every episode that took one value in one state.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Estimate {
+    pub visits: u64,
+    // episodes in which a constraint failed
+    pub failures: u64,
+    // the body summed over the other episodes
+    pub total: f64,
+}
+
+impl Estimate {
+    pub fn failure_rate(&self) -> f64 {
+        self.failures as f64 / self.visits as f64
+    }
+
+    // mean body when every constraint held
+    pub fn mean(&self) -> f64 {
+        match self.visits - self.failures {
+            0 => f64::INFINITY,
+            n => self.total / n as f64,
+        }
+    }
+
+    // Lower is better: fewer failures first, then a lower body.
+    fn better_than(&self, other: &Estimate) -> bool {
+        self.failure_rate().total_cmp(&other.failure_rate())
+            .then(self.mean().total_cmp(&other.mean()))
+            .is_lt()
+    }
+}
 
 pub struct Q {
-    // store active and previous
-    q0: Array2<f32>,
-    q1: Array2<f32>,
+    // store active and previous: episodes read q0 while a round runs, and their
+    // updates go to q1
+    q0: HashMap<State, Vec<Estimate>>,
+    q1: HashMap<State, Vec<Estimate>>,
 }
 
 impl Q {
-    pub fn new(_graph: &ScpGraph) -> Q {
-        todo!()
-        // get all stochastic variables, take the product of their cardinality
-        // get all decision variables, take the product of their cardinality
-        // allocate new Qs, whichhot = false
+    pub fn new() -> Q {
+        Q{q0: HashMap::new(), q1: HashMap::new()}
     }
 
     pub fn end_round(&mut self) {
-        todo!()
-        // do some stuff
-        // swap the buffers
+        self.q0 = self.q1.clone();
     }
 
-    // other useful functions go here
+    pub fn states(&self) -> usize {
+        self.q0.len()
+    }
+
+    // The value to take in `state`: a random one with probability `EPSILON`, then
+    // any value not yet tried, then the best so far.
+    fn choose<R: Rng + ?Sized>(&self, state: &State, cardinality: usize, rng: &mut R) -> usize {
+        match self.q0.get(state) {
+            Some(estimates) if rng.gen::<f64>() >= EPSILON => best(estimates),
+            _ => rng.gen_range(0..cardinality),
+        }
+    }
+
+    // Counts the episode against every value it took.
+    fn update(&mut self, taken: &Taken, cost: Option<f64>) {
+        for (state, cardinality, action) in taken {
+            let estimate = &mut self.q1.entry(state.clone()).or_insert_with(|| vec![Estimate::default(); *cardinality])[*action];
+            estimate.visits += 1;
+            match cost {
+                Some(c) => estimate.total += c,
+                None => estimate.failures += 1,
+            }
+        }
+    }
+
+    /// The best value of each decision made before anything is observed, and how
+    /// it did.
+    pub fn first_stage(&self, graph: &ScpGraph) -> Result<Vec<(Identifier, Primitive, Estimate)>, String> {
+        let mut values = HashMap::new();
+        let mut decided = Vec::new();
+        for var in graph.stages.first().map_or(&[][..], |stage| &stage.decision[..]) {
+            let domain = domain(graph, *var, &values)?;
+            let (value, estimate) = match self.q0.get(&(var.id, SmallVec::new())) {
+                Some(estimates) => {
+                    let i = best(estimates);
+                    (domain.nth(i), estimates[i])
+                }
+                None => break,
+            };
+            let name = graph.variables.name(*var).clone();
+            values.insert(name.clone(), value.clone());
+            decided.push((name, value, estimate));
+        }
+        Ok(decided)
+    }
 }
 
-pub fn q_learn(graph: &ScpGraph) -> Q {
-    let mut q = Q::new(graph);
+// the first value not yet tried, or else the best
+fn best(estimates: &[Estimate]) -> usize {
+    if let Some(i) = estimates.iter().position(|e| e.visits == 0) {
+        return i;
+    }
+    let mut best = 0;
+    for (i, e) in estimates.iter().enumerate() {
+        if e.better_than(&estimates[best]) {
+            best = i;
+        }
+    }
+    best
+}
+
+fn domain(graph: &ScpGraph, var: VarRef, values: &HashMap<Identifier, Primitive>) -> Result<Domain, String> {
+    let definition = &graph.variables.deref(var).definition;
+    match scenario::eval(definition, definition.root(), values)? {
+        Primitive::Domain(d) => Ok(d),
+        other => Err(format!("{:?} is not a domain", other)),
+    }
+}
+
+// One episode under the ε-greedy policy: what each decision took, and the
+// episode's body, or `None` if a constraint failed.
+fn episode(graph: &ScpGraph, q: &Q, rng: &mut StdRng) -> Result<(Taken, Option<f64>), String> {
+    let mut taken = Vec::new();
+    let values = scenario::simulate(graph, rng, |var, domain, observed, rng| {
+        let state = (var.id, SmallVec::from_slice(observed));
+        let action = q.choose(&state, domain.cardinality(), rng);
+        taken.push((state, domain.cardinality(), action));
+        action
+    })?;
+    let cost = solve::cost(graph, &values).map_err(|e| e.to_string())?;
+    Ok((taken, cost))
+}
+
+/// Learns which value to give each decision from `episodes` simulated episodes.
+/// Episode `n` draws from `seed.child(n)`, and each round's episodes only read
+/// the table from before the round, so the result is the same however the
+/// episodes are spread over threads.
+pub fn q_learn(graph: &ScpGraph, seed: random::Seed, episodes: u64) -> Result<Q, String> {
+    let mut q = Q::new();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
 
+    let mut start = 0;
+    while start < episodes {
+        let end = (start + ROUND).min(episodes);
+        let mut results = std::thread::scope(|s| {
+            let q = &q;
+            let workers: Vec<_> = (0..threads).map(|t| s.spawn(move || {
+                (start + t..end).step_by(threads as usize)
+                    .map(|n| (n, episode(graph, q, &mut seed.child(n).rng())))
+                    .collect::<Vec<_>>()
+            })).collect();
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect::<Vec<_>>()
+        });
+        // apply the updates in episode order
+        results.sort_by_key(|(n, _)| *n);
+        for (_, result) in results {
+            let (taken, cost) = result?;
+            q.update(&taken, cost);
+        }
+        q.end_round();
+        start = end;
+    }
+
+    Ok(q)
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x, then d from {1, 2, 4}, then y, with x + y >= d and body 2x + 3y
+    fn two_stage() -> ScpGraph {
+        json::from_json(include_str!("q/two_stage.json")).unwrap()
+    }
 
-    q
-}
\ No newline at end of file
+    #[test]
+    fn same_seed_same_policy() {
+        let graph = two_stage();
+        let a = q_learn(&graph, random::Seed::new(Some(7)), 1000).unwrap();
+        let b = q_learn(&graph, random::Seed::new(Some(7)), 1000).unwrap();
+        assert_eq!(a.q0, b.q0);
+        assert_eq!(a.first_stage(&graph).unwrap(), b.first_stage(&graph).unwrap());
+    }
+
+    #[test]
+    fn first_stage_is_decided_before_the_sample() {
+        let graph = two_stage();
+        let q = q_learn(&graph, random::Seed::new(Some(7)), 1000).unwrap();
+        let decided = q.first_stage(&graph).unwrap();
+        assert_eq!(decided.len(), 1);
+        assert_eq!(decided[0].0, graph.variables.name(graph.stages[0].decision[0]).clone());
+        // y only ever sees d's outcome, never x
+        for (var, observed) in q.q0.keys() {
+            let stage = graph.stages.iter().position(|s| s.decision.iter().any(|d| d.id == *var)).unwrap();
+            let revealed: usize = graph.stages[..stage].iter().map(|s| s.stochastic.len()).sum();
+            assert_eq!(observed.len(), revealed);
+        }
+    }
+}